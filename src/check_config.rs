@@ -0,0 +1,208 @@
+use std::time::Duration;
+
+use evdev::KeyCode;
+
+use crate::cmd::TimeoutRange;
+use crate::config::FilterSettings;
+use crate::key_spec::{collect_conflicts, resolve_key_rules, KeyRule, KEY_MAX};
+
+struct Row {
+    code: u16,
+    timeout: Option<Duration>,
+    rule: Option<usize>,
+}
+
+/// The effective timeout of every key, as `KeyFilter` resolves it, along with the problems found in the rules
+pub(super) struct RuleTable {
+    key_rules: Vec<KeyRule>,
+    rows: Vec<Row>,
+    conflicts: Vec<String>,
+    out_of_range: Vec<String>,
+}
+
+impl RuleTable {
+    pub(super) fn new(settings: &FilterSettings, codes: &[u16]) -> Self {
+        let key_rules = settings
+            .timeouts
+            .iter()
+            .filter_map(|timeout_range| match timeout_range {
+                TimeoutRange::Key(key_rule) => Some(key_rule.clone()),
+                TimeoutRange::Scan(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let resolutions = resolve_key_rules(&key_rules, KEY_MAX as usize + 1);
+
+        let rows = codes
+            .iter()
+            .map(|&code| {
+                let rule = resolutions[code as usize].as_ref().map(|resolution| resolution.rule);
+                Row {
+                    code,
+                    timeout: rule.map(|rule| key_rules[rule].timeout),
+                    rule,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Only the conflicts on the checked keys matter
+        let checked = codes
+            .iter()
+            .map(|&code| resolutions[code as usize].clone())
+            .collect::<Vec<_>>();
+        let conflicts = collect_conflicts(&checked)
+            .into_iter()
+            .map(|((winner, loser), count)| {
+                format!(
+                    "{} and {} are equally specific and overlap on {count} key(s); {} wins",
+                    key_rules[winner].source, key_rules[loser].source, key_rules[winner].source
+                )
+            })
+            .collect();
+
+        // KeyFilter only builds its table up to the highest code of the device, the keys above are dropped
+        let max_code = codes.iter().copied().max();
+        let out_of_range = key_rules
+            .iter()
+            .filter_map(|key_rule| {
+                let above = max_code.map(|max_code| key_rule.keys.ranges_above(max_code));
+                match above {
+                    Some(above) if !above.is_empty() => {
+                        let count = above.iter().map(|range| range.len()).sum::<usize>();
+                        let above = above
+                            .iter()
+                            .map(|range| match range.start() == range.end() {
+                                true => format!("{:?}", KeyCode::new(*range.start())),
+                                false => format!("{}..{}", range.start(), range.end()),
+                            })
+                            .collect::<Vec<_>>();
+                        Some(format!(
+                            "{} covers {count} key(s) above the checked range (max code {}): {}",
+                            key_rule.source,
+                            max_code.unwrap_or_default(),
+                            above.join(", ")
+                        ))
+                    }
+                    _ if !codes.iter().any(|&code| key_rule.keys.contains(code)) => {
+                        Some(format!("{} covers none of the checked keys", key_rule.source))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Self {
+            key_rules,
+            rows,
+            conflicts,
+            out_of_range,
+        }
+    }
+
+    pub(super) fn has_problems(&self) -> bool {
+        !self.conflicts.is_empty() || !self.out_of_range.is_empty()
+    }
+
+    pub(super) fn print(&self, settings: &FilterSettings, f: &mut impl std::io::Write) -> std::io::Result<()> {
+        let names = self
+            .rows
+            .iter()
+            .map(|row| format!("{:?}", KeyCode::new(row.code)))
+            .collect::<Vec<_>>();
+        let name_width = names.iter().map(String::len).max().unwrap_or_default().max(4);
+
+        writeln!(f, "{:>5}  {:<name_width$}  {:>9}  RULE", "CODE", "NAME", "TIMEOUT")?;
+        for (row, name) in self.rows.iter().zip(&names) {
+            let timeout = row.timeout.map(|timeout| format!("{timeout:?}"));
+            let rule = row.rule.map(|rule| self.key_rules[rule].source.as_ref());
+            writeln!(
+                f,
+                "{:>5}  {:<name_width$}  {:>9}  {}",
+                row.code,
+                name,
+                timeout.as_deref().unwrap_or("-"),
+                rule.unwrap_or("-")
+            )?;
+        }
+
+        let scan_rules = settings
+            .timeouts
+            .iter()
+            .filter_map(|timeout_range| match timeout_range {
+                TimeoutRange::Scan(scan_range_timeout) => Some(format!(
+                    "{:#x}..={:#x}: {:?}",
+                    scan_range_timeout.range.start(),
+                    scan_range_timeout.range.end(),
+                    scan_range_timeout.timeout
+                )),
+                TimeoutRange::Key(_) => None,
+            })
+            .collect::<Vec<_>>();
+        if !scan_rules.is_empty() {
+            writeln!(
+                f,
+                "\nScan code rules, applied before the key rules: {}",
+                scan_rules.join(", ")
+            )?;
+        }
+
+        for conflict in &self.conflicts {
+            writeln!(f, "Conflict: {conflict}")?;
+        }
+        for out_of_range in &self.out_of_range {
+            writeln!(f, "Out of range: {out_of_range}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::parse_timeout_range;
+
+    #[test]
+    fn test_rule_table() {
+        let settings = FilterSettings {
+            timeouts: ["all:70", "KEY_A:20", "KEY_B:30", "KEY_B:40"]
+                .iter()
+                .map(|raw| parse_timeout_range(raw).unwrap())
+                .collect(),
+            ..Default::default()
+        };
+        let codes = [KeyCode::KEY_A.0, KeyCode::KEY_B.0, KeyCode::KEY_C.0];
+
+        let table = RuleTable::new(&settings, &codes);
+        let timeouts = table.rows.iter().map(|row| row.timeout).collect::<Vec<_>>();
+        assert_eq!(
+            timeouts,
+            [20, 30, 70].map(|timeout| Some(Duration::from_millis(timeout)))
+        );
+        assert_eq!(table.conflicts.len(), 1);
+        assert!(table.out_of_range.is_empty());
+        assert!(table.has_problems());
+
+        let mut out = vec![];
+        table.print(&settings, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("KEY_C"));
+        assert!(out.contains("Conflict: KEY_B:30 and KEY_B:40"));
+
+        let table = RuleTable::new(&settings, &[KeyCode::KEY_C.0]);
+        assert_eq!(table.out_of_range.len(), 3);
+
+        // `all` follows the checked keys, explicit keys above them don't apply
+        let settings = FilterSettings {
+            timeouts: ["all:70", "KEY_A..0x2ff:70", "0:1000:30", "KEY_A,BTN_LEFT:20"]
+                .iter()
+                .map(|raw| parse_timeout_range(raw).unwrap())
+                .collect(),
+            ..Default::default()
+        };
+        let table = RuleTable::new(&settings, &[KeyCode::KEY_A.0, KeyCode::KEY_B.0]);
+        assert_eq!(table.out_of_range.len(), 3);
+        assert!(table.out_of_range[0].contains("KEY_A..0x2ff:70 covers 719 key(s)"));
+        assert!(table.out_of_range[1].contains(": 49..1000"));
+        assert!(table.out_of_range[2].ends_with(": BTN_LEFT"));
+        assert!(table.has_problems());
+    }
+}
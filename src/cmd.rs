@@ -1,8 +1,18 @@
+use std::collections::HashMap;
 use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use evdev::{BusType, EventType, InputId, KeyCode};
+use glob::Pattern;
+use regex::{Regex, RegexBuilder};
+
+use crate::device_wrapper::{aliases_of, DeviceWrapper};
+use crate::key_spec::{parse_key_rule, parse_key_spec, KeyRule, KeySpec};
+use crate::udev::udev_properties;
 
 /// Debounce / de-chattering utility for key input devices.
 #[derive(Parser, Debug)]
@@ -15,7 +25,7 @@ pub(super) struct Cli {
 
 #[derive(Subcommand, Debug)]
 pub(super) enum Command {
-    /// List all input devices
+    /// List all input devices, or the ones matching the filters along with the index de-chatter would give them
     List {
         /// Show the path to the input device
         #[arg(short, long, default_value = "true")]
@@ -37,40 +47,421 @@ pub(super) enum Command {
         #[arg(short, long, default_value = "false")]
         keys: bool,
 
-        /// Enable all flags: (-Ppnik)
+        /// Show the input properties, event types, LEDs and switches of the input device
+        #[arg(short, long, default_value = "false")]
+        capabilities: bool,
+
+        /// Show the autorepeat delay and period of the input device
+        #[arg(short, long, default_value = "false")]
+        repeat: bool,
+
+        /// Show the unique identifier (usually the serial number) of the input device
+        #[arg(short, long, default_value = "false")]
+        uniq: bool,
+
+        /// Show the /dev/input/by-id and /dev/input/by-path symlinks to the input device
+        #[arg(short = 'l', long, default_value = "false")]
+        aliases: bool,
+
+        /// Show whether another process has grabbed the input device, and whether it is a virtual device of
+        /// dechat-rs. Intrusive: the device is grabbed for an instant to find out, so a key released meanwhile can
+        /// be lost; not included in --all
+        #[arg(short, long, default_value = "false")]
+        status: bool,
+
+        /// Enable all flags but --status: (-Ppnikcrul)
         #[arg(short, long, default_value = "false")]
         all: bool,
+
+        /// Output format; colors are disabled when stdout is not a terminal
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// Keep running and print the devices as they are added or removed (the index is the one at that moment)
+        #[arg(short, long, default_value_t = false)]
+        watch: bool,
+
+        #[command(flatten)]
+        filters: ListFilters,
     },
 
     /// Grab the device and de-chatter it.
-    /// Filters support prefixes: s: starts with, e: ends with, c: contains, no prefix means equals.
+    /// Filters support prefixes: s: starts with, e: ends with, c: contains, r: regex, g: glob, no prefix means equals.
+    /// Append i to the prefix to ignore case (si:, ei:, ci:, ri:, gi:, i:), and prepend ! to negate (!c:Mouse).
     DeChatter {
-        /// Inclusive ranges of keys to de-chatter, in format <start>:<end>:<timeout_ms> (repeatable)
-        #[arg(short, long, value_parser = parse_key_range)]
-        timeouts: Vec<KeyRangeTimeout>,
+        /// Keys to de-chatter, in format <keys>:<timeout> (repeatable). Keys are comma-separated key names, codes,
+        /// inclusive ranges of them by code (KEY_Q..KEY_P), or groups (all, letters, digits, modifiers, arrows,
+        /// numpad, function); prefix with ! to exclude, i.e. letters:70ms or all,!KEY_SPACE:70. The timeout is in ms
+        /// unless suffixed with ms, us or s. More specific rules (covering fewer keys) win. The format
+        /// <start>:<end>:<timeout_ms> works too.
+        /// Prefix with scan: to match hardware scan codes (MSC_SCAN) instead of key codes,
+        /// i.e. scan:0x70004:0x7001d:70
+        #[arg(short, long, value_parser = parse_timeout_range)]
+        timeouts: Vec<TimeoutRange>,
 
-        /// Repeatable device name filter (Example: -n s:'Asus')
-        #[arg(short, long, value_parser = parse_filter)]
-        name: Vec<StringFilter>,
+        /// Inclusive ranges of switches (EV_SW) to debounce, in format <start>:<end>:<timeout_ms> (repeatable).
+        /// A switch change is forwarded only after it stays unchanged for the timeout
+        #[arg(short = 'S', long, value_parser = parse_key_range)]
+        switch_timeouts: Vec<KeyRangeTimeout>,
 
-        /// Repeatable device path filter (Example: -p '/dev/input/event10')
-        #[arg(short, long, value_parser = parse_filter)]
-        path: Vec<StringFilter>, // TODO: path filter must be PathBuf/OsString, not String
+        /// Drop scroll wheel ticks that contradict the recent scroll direction within this timeout, ms
+        #[arg(long, value_parser = parse_timeout)]
+        wheel_reversal: Option<Duration>,
 
-        /// Repeatable device physical path filter (Example: -P 'usb-0000:04:00.3-3/input2')
-        #[arg(short = 'P', long, value_parser = parse_filter)]
-        physical_path: Vec<StringFilter>,
+        /// TOML file with timeouts and per-device profiles, plus drop-ins from the sibling <name>.d directory;
+        /// the flags win over the file. Re-read on SIGHUP without releasing the device
+        /// [default: /etc/dechat-rs/config.toml, if it exists]
+        #[arg(short, long, value_name = "PATH")]
+        config: Option<PathBuf>,
+
+        /// Reload the config file as soon as it changes
+        #[arg(long, default_value_t = false)]
+        watch_config: bool,
+
+        #[command(flatten)]
+        filters: DeviceFilters,
 
         /// Take the device with this index after applying all filters
         #[arg(short = 'i', long, default_value_t = 0)]
         index: usize,
 
-        /// Skip the first event batch: if we start the program is started from terminal,
-        /// the last ENTER key we press gets stuck, and also it alarms the touchegg service -
-        /// `Touchpad: kernel bug: Touch jump detected and discarded.`
-        #[arg(short = 's', long, default_value_t = false)]
+        /// Wait for the device to appear instead of failing, optionally for at most this timeout, ms
+        #[arg(short = 'W', long, value_name = "TIMEOUT_MS", value_parser = parse_timeout)]
+        wait: Option<Option<Duration>>,
+
+        /// Take every device matching the filters, each with its own virtual device
+        #[arg(short = 'a', long, default_value_t = false, conflicts_with = "index")]
+        all: bool,
+
+        /// Take every device matching the filters and feed them all into a single virtual device.
+        /// Chatter is still tracked per source device
+        #[arg(short = 'm', long, default_value_t = false, conflicts_with_all = ["index", "all"])]
+        merge: bool,
+
+        /// The original device is grabbed once all its keys are released; if some keys are still held after
+        /// this timeout, ms, a warning names them and the grab keeps waiting for their release [default: 3000]
+        #[arg(short = 'g', long, value_parser = parse_timeout)]
+        grab_timeout: Option<Duration>,
+
+        /// Deprecated and ignored: the grab is postponed until all keys are released
+        #[arg(short = 's', long, default_value_t = false, hide = true)]
         skip_first: bool,
     },
+
+    /// Print the effective timeout of every key of a device (or of a key list) and the rule it comes from.
+    /// Exits with a non-zero status if rules conflict, cover none of the keys, or name keys above the checked range
+    CheckConfig {
+        /// Keys to de-chatter, in the same format as for de-chatter (repeatable)
+        #[arg(short, long, value_parser = parse_timeout_range)]
+        timeouts: Vec<TimeoutRange>,
+
+        /// TOML config file, as for de-chatter [default: /etc/dechat-rs/config.toml, if it exists]
+        #[arg(short, long, value_name = "PATH")]
+        config: Option<PathBuf>,
+
+        /// Check these keys instead of the keys of a device, i.e. letters,KEY_SPACE or 0..255
+        #[arg(short, long, value_parser = parse_key_spec)]
+        keys: Option<KeySpec>,
+
+        #[command(flatten)]
+        filters: DeviceFilters,
+
+        /// Take the device with this index after applying all filters
+        #[arg(short = 'i', long, default_value_t = 0)]
+        index: usize,
+    },
+
+    /// Wait for a key press on any keyboard (without grabbing them) and print the device it came from, along with
+    /// the de-chatter filters selecting it
+    Identify {
+        /// Give up if no key is pressed within this timeout, ms [default: wait forever]
+        #[arg(short = 'W', long, value_name = "TIMEOUT_MS", value_parser = parse_timeout)]
+        wait: Option<Duration>,
+
+        /// Start de-chattering the device right away
+        #[arg(short, long, default_value_t = false)]
+        start: bool,
+
+        /// Keys to de-chatter once started, in the same format as for de-chatter (repeatable)
+        #[arg(short, long, value_parser = parse_timeout_range, requires = "start")]
+        timeouts: Vec<TimeoutRange>,
+
+        /// TOML config file to de-chatter with once started, as for de-chatter
+        /// [default: /etc/dechat-rs/config.toml, if it exists]
+        #[arg(short, long, value_name = "PATH", requires = "start")]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum OutputFormat {
+    /// Colorized key=value pairs, one device per line
+    Text,
+    /// An array of device records
+    Json,
+    /// A header row, then a row per device; keys are space-separated NAME=CODE pairs
+    Csv,
+    /// Aligned columns
+    Table,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub(super) struct DeviceFilters {
+    /// Repeatable device name filter (Example: -n s:'Asus')
+    #[arg(short, long, value_parser = parse_filter)]
+    pub(super) name: Vec<StringFilter>,
+
+    /// Repeatable device path filter; symlinks such as /dev/input/by-id/* are resolved, and prefixed filters match
+    /// the by-id and by-path aliases too (Example: -p '/dev/input/event10', -p g:'/dev/input/by-id/*Asus*-kbd')
+    #[arg(short, long, value_parser = parse_path_filter)]
+    pub(super) path: Vec<PathFilter>,
+
+    /// Repeatable device physical path filter (Example: -P 'usb-0000:04:00.3-3/input2')
+    #[arg(short = 'P', long, value_parser = parse_filter)]
+    pub(super) physical_path: Vec<StringFilter>,
+
+    /// Repeatable bus type filter: a name or a number (Example: --bus usb)
+    #[arg(long, value_parser = parse_bus)]
+    pub(super) bus: Vec<BusType>,
+
+    /// Repeatable vendor and product ID filter, hex, in format <vendor>[:<product>[:<version>]]
+    /// (Example: --id 0b05:19b6)
+    #[arg(long, value_parser = parse_id_filter)]
+    pub(super) id: Vec<IdFilter>,
+
+    /// Repeatable unique identifier (serial number) filter (Example: --uniq c:'ABC123')
+    #[arg(long, value_parser = parse_filter)]
+    pub(super) uniq: Vec<StringFilter>,
+
+    /// Repeatable filter on the supported keys: the device must have all of them; keys are given as for
+    /// check-config --keys (Example: --has-key KEY_A, --has-key letters,KEY_SPACE)
+    #[arg(long, value_name = "KEYS", value_parser = parse_key_spec)]
+    pub(super) has_key: Vec<KeySpec>,
+
+    /// Repeatable filter on the supported event types (Example: --has-event-type REL)
+    #[arg(long, value_name = "TYPE", value_parser = parse_event_type)]
+    pub(super) has_event_type: Vec<EventType>,
+
+    /// Repeatable udev property filter, in format <KEY>=<VALUE>, where the value takes the same prefixes as the
+    /// name; a missing property counts as empty (Example: --udev-prop ID_INPUT_KEYBOARD=1)
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_udev_prop)]
+    pub(super) udev_prop: Vec<UdevPropFilter>,
+
+    /// Take the devices with all letters and digits, except YubiKeys and the virtual devices of dechat-rs; implies
+    /// --all unless --merge is given
+    #[arg(long, default_value_t = false, conflicts_with = "index")]
+    pub(super) auto_keyboards: bool,
+}
+
+/// The device filters of de-chatter, renamed so as not to clash with the list flags; the ones that don't clash take
+/// the de-chatter spelling too, so a selection can be copied over as is
+#[derive(Args, Debug, Clone, Default)]
+#[command(next_help_heading = "Filters")]
+pub(super) struct ListFilters {
+    /// Repeatable device name filter (Example: --filter-name s:'Asus')
+    #[arg(id = "filter_name", long = "filter-name", value_name = "NAME", value_parser = parse_filter)]
+    name: Vec<StringFilter>,
+
+    /// Repeatable device path filter, as --path of de-chatter (Example: --filter-path '/dev/input/event10')
+    #[arg(id = "filter_path", long = "filter-path", value_name = "PATH", value_parser = parse_path_filter)]
+    path: Vec<PathFilter>,
+
+    /// Repeatable device physical path filter (Example: --filter-physical-path 'usb-0000:04:00.3-3/input2')
+    #[arg(
+        id = "filter_physical_path",
+        long = "filter-physical-path",
+        value_name = "PHYSICAL_PATH",
+        value_parser = parse_filter
+    )]
+    physical_path: Vec<StringFilter>,
+
+    /// Repeatable bus type filter: a name or a number (Example: --filter-bus usb)
+    #[arg(
+        id = "filter_bus",
+        long = "filter-bus",
+        visible_alias = "bus",
+        value_name = "BUS",
+        value_parser = parse_bus
+    )]
+    bus: Vec<BusType>,
+
+    /// Repeatable vendor and product ID filter, hex, in format <vendor>[:<product>[:<version>]]
+    /// (Example: --filter-id 0b05:19b6)
+    #[arg(id = "filter_id", long = "filter-id", value_name = "ID", value_parser = parse_id_filter)]
+    id: Vec<IdFilter>,
+
+    /// Repeatable unique identifier (serial number) filter (Example: --filter-uniq c:'ABC123')
+    #[arg(id = "filter_uniq", long = "filter-uniq", value_name = "UNIQ", value_parser = parse_filter)]
+    uniq: Vec<StringFilter>,
+
+    /// Repeatable filter on the supported keys, as --has-key of de-chatter (Example: --filter-has-key KEY_A)
+    #[arg(
+        id = "filter_has_key",
+        long = "filter-has-key",
+        visible_alias = "has-key",
+        value_name = "KEYS",
+        value_parser = parse_key_spec
+    )]
+    has_key: Vec<KeySpec>,
+
+    /// Repeatable filter on the supported event types (Example: --filter-has-event-type REL)
+    #[arg(
+        id = "filter_has_event_type",
+        long = "filter-has-event-type",
+        visible_alias = "has-event-type",
+        value_name = "TYPE",
+        value_parser = parse_event_type
+    )]
+    has_event_type: Vec<EventType>,
+
+    /// Repeatable udev property filter, as --udev-prop of de-chatter (Example: --filter-udev-prop ID_INPUT_KEYBOARD=1)
+    #[arg(
+        id = "filter_udev_prop",
+        long = "filter-udev-prop",
+        visible_alias = "udev-prop",
+        value_name = "KEY=VALUE",
+        value_parser = parse_udev_prop
+    )]
+    udev_prop: Vec<UdevPropFilter>,
+
+    /// Only the devices de-chatter --auto-keyboards would take
+    #[arg(
+        id = "filter_auto_keyboards",
+        long = "filter-auto-keyboards",
+        visible_alias = "auto-keyboards",
+        default_value_t = false
+    )]
+    auto_keyboards: bool,
+}
+
+impl From<ListFilters> for DeviceFilters {
+    fn from(filters: ListFilters) -> Self {
+        Self {
+            name: filters.name,
+            path: filters.path,
+            physical_path: filters.physical_path,
+            bus: filters.bus,
+            id: filters.id,
+            uniq: filters.uniq,
+            has_key: filters.has_key,
+            has_event_type: filters.has_event_type,
+            udev_prop: filters.udev_prop,
+            auto_keyboards: filters.auto_keyboards,
+        }
+    }
+}
+
+impl DeviceFilters {
+    pub(super) fn is_empty(&self) -> bool {
+        self.name.is_empty()
+            && self.path.is_empty()
+            && self.physical_path.is_empty()
+            && self.bus.is_empty()
+            && self.id.is_empty()
+            && self.uniq.is_empty()
+            && self.has_key.is_empty()
+            && self.has_event_type.is_empty()
+            && self.udev_prop.is_empty()
+            && !self.auto_keyboards
+    }
+
+    pub(super) fn matches(&self, device_wrapper: &DeviceWrapper) -> bool {
+        self.path.iter().all(|filter| filter.matches(&device_wrapper.path))
+            && self
+                .name
+                .iter()
+                .all(|filter| filter.matches(device_wrapper.device.name().unwrap_or_default()))
+            && self
+                .physical_path
+                .iter()
+                .all(|filter| filter.matches(device_wrapper.device.physical_path().unwrap_or_default()))
+            && self
+                .bus
+                .iter()
+                .all(|&bus| device_wrapper.device.input_id().bus_type() == bus)
+            && self
+                .id
+                .iter()
+                .all(|filter| filter.matches(&device_wrapper.device.input_id()))
+            && self
+                .uniq
+                .iter()
+                .all(|filter| filter.matches(device_wrapper.device.unique_name().unwrap_or_default()))
+            && self.has_key.iter().all(|keys| {
+                let supported = device_wrapper.device.supported_keys();
+                keys.codes()
+                    .all(|code| supported.is_some_and(|supported| supported.contains(KeyCode(code))))
+            })
+            && self
+                .has_event_type
+                .iter()
+                .all(|&event_type| device_wrapper.device.supported_events().contains(event_type))
+            && (self.udev_prop.is_empty() || {
+                let properties = udev_properties(&device_wrapper.path);
+                self.udev_prop.iter().all(|filter| filter.matches(&properties))
+            })
+            && (!self.auto_keyboards || device_wrapper.is_keyboard())
+    }
+}
+
+/// A filter on a property of the udev database, i.e. `ID_INPUT_KEYBOARD=1` or `ID_SERIAL=c:N-KEY`
+#[derive(Debug, Clone)]
+pub(super) struct UdevPropFilter {
+    key: Arc<str>,
+    value: StringFilter,
+}
+
+impl UdevPropFilter {
+    fn matches(&self, properties: &HashMap<String, String>) -> bool {
+        let value = properties.get(self.key.as_ref()).map(String::as_str);
+        self.value.matches(value.unwrap_or_default())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum PathFilter {
+    /// Matches the node the path resolves to, so that a stable by-id or by-path symlink selects its device
+    Equals(PathBuf),
+    /// Matches the node path or any of its by-id and by-path aliases
+    Pattern(StringFilter),
+    /// Matches if the inner filter matches none of the node path and its aliases
+    Not(Box<PathFilter>),
+}
+
+impl PathFilter {
+    pub(super) fn matches(&self, path: &Path) -> bool {
+        match self {
+            PathFilter::Equals(filter) => {
+                filter == path
+                    || filter
+                        .canonicalize()
+                        .is_ok_and(|target| path.canonicalize().is_ok_and(|node| node == target))
+            }
+            PathFilter::Pattern(filter) => {
+                filter.matches(path.to_string_lossy())
+                    || aliases_of(path)
+                        .iter()
+                        .any(|alias| filter.matches(alias.to_string_lossy()))
+            }
+            PathFilter::Not(filter) => !filter.matches(path),
+        }
+    }
+}
+
+/// Vendor and product IDs, and optionally the version, as the kernel reports them in `input_id`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct IdFilter {
+    vendor: u16,
+    product: Option<u16>,
+    version: Option<u16>,
+}
+
+impl IdFilter {
+    fn matches(&self, input_id: &InputId) -> bool {
+        input_id.vendor() == self.vendor
+            && self.product.is_none_or(|product| input_id.product() == product)
+            && self.version.is_none_or(|version| input_id.version() == version)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -79,6 +470,13 @@ pub(super) enum StringFilter {
     Equals(Arc<str>),
     Contains(Arc<str>),
     EndsWidth(Arc<str>),
+    /// Matches anywhere unless anchored
+    Regex(Regex),
+    /// Matches the whole string
+    Glob(Pattern),
+    /// Matches the lowercased string; the inner filter is built from the lowercased pattern
+    IgnoreCase(Box<StringFilter>),
+    Not(Box<StringFilter>),
 }
 
 impl StringFilter {
@@ -89,6 +487,10 @@ impl StringFilter {
             StringFilter::Equals(filter) => raw == filter.as_ref(),
             StringFilter::Contains(filter) => raw.contains(filter.as_ref()),
             StringFilter::EndsWidth(filter) => raw.ends_with(filter.as_ref()),
+            StringFilter::Regex(regex) => regex.is_match(raw),
+            StringFilter::Glob(pattern) => pattern.matches(raw),
+            StringFilter::IgnoreCase(filter) => filter.matches(raw.to_lowercase()),
+            StringFilter::Not(filter) => !filter.matches(raw),
         }
     }
 }
@@ -99,46 +501,230 @@ pub(super) struct KeyRangeTimeout {
     pub(super) timeout: Duration,
 }
 
-fn parse_key_range(raw: &str) -> Result<KeyRangeTimeout, String> {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct ScanRangeTimeout {
+    pub(super) range: RangeInclusive<u32>,
+    pub(super) timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum TimeoutRange {
+    Key(KeyRule),
+    Scan(ScanRangeTimeout),
+}
+
+pub(super) fn parse_timeout_range(raw: &str) -> Result<TimeoutRange, String> {
+    if let Some(raw) = raw.strip_prefix("scan:") {
+        parse_scan_range(raw).map(TimeoutRange::Scan)
+    } else {
+        parse_key_rule(raw).map(TimeoutRange::Key)
+    }
+}
+
+fn parse_scan_code(raw: &str) -> Result<u32, std::num::ParseIntError> {
+    match raw.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => raw.parse::<u32>(),
+    }
+}
+
+fn parse_scan_range(raw: &str) -> Result<ScanRangeTimeout, String> {
     let parts = raw.splitn(3, ':').collect::<Vec<_>>();
     if parts.len() != 3 {
-        return Err(format!("Invalid key range: {}", raw));
+        return Err(format!("Invalid scan code range: {}", raw));
     }
-    let parts = match parts
-        .iter()
-        .map(|part| part.parse::<u16>())
-        .collect::<Result<Vec<_>, _>>()
-    {
-        Ok(parts) => parts,
-        Err(err) => return Err(format!("Invalid key range {raw}: {}", err)),
+    let (start, end) = match (parse_scan_code(parts[0]), parse_scan_code(parts[1])) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(err), _) | (_, Err(err)) => return Err(format!("Invalid scan code range {raw}: {}", err)),
     };
 
-    let range = parts[0]..=parts[1];
+    let range = start..=end;
     if range.is_empty() {
-        return Err(format!("Invalid empty range set for key range {}", raw));
+        return Err(format!("Invalid empty range set for scan code range {}", raw));
     }
 
-    let timeout = Duration::from_millis(parts[2] as u64);
+    let timeout = parse_timeout(parts[2])?;
 
-    if timeout.as_millis() == 0 {
-        return Err(format!("Invalid zero timeout set for key range {}", raw));
+    Ok(ScanRangeTimeout { range, timeout })
+}
+
+pub(super) fn parse_key_range(raw: &str) -> Result<KeyRangeTimeout, String> {
+    let parts = raw.splitn(3, ':').collect::<Vec<_>>();
+    if parts.len() != 3 {
+        return Err(format!("Invalid key range: {}", raw));
     }
+    let range = match (parts[0].parse::<u16>(), parts[1].parse::<u16>()) {
+        (Ok(start), Ok(end)) => start..=end,
+        (Err(err), _) | (_, Err(err)) => return Err(format!("Invalid key range {raw}: {}", err)),
+    };
+    if range.is_empty() {
+        return Err(format!("Invalid empty range set for key range {}", raw));
+    }
+
+    let timeout = parse_timeout(parts[2])?;
 
     Ok(KeyRangeTimeout { range, timeout })
 }
 
-fn parse_filter(raw: &str) -> Result<StringFilter, String> {
-    if let Some(raw) = raw.strip_prefix("s:") {
-        Ok(StringFilter::StartsWidth(raw.into()))
-    } else if let Some(raw) = raw.strip_prefix("e:") {
-        Ok(StringFilter::EndsWidth(raw.into()))
-    } else if let Some(raw) = raw.strip_prefix("c:") {
-        Ok(StringFilter::Contains(raw.into()))
+/// Milliseconds, unless suffixed with a unit: `ms`, `us` or `s`
+pub(super) fn parse_timeout(raw: &str) -> Result<Duration, String> {
+    let (value, from_unit): (&str, fn(u64) -> Duration) = if let Some(value) = raw.strip_suffix("ms") {
+        (value, Duration::from_millis)
+    } else if let Some(value) = raw.strip_suffix("us") {
+        (value, Duration::from_micros)
+    } else if let Some(value) = raw.strip_suffix('s') {
+        (value, Duration::from_secs)
+    } else {
+        (raw, Duration::from_millis)
+    };
+    let timeout = match value.parse::<u64>() {
+        Ok(value) => from_unit(value),
+        Err(err) => return Err(format!("Invalid timeout {raw}: {}", err)),
+    };
+
+    if timeout.is_zero() {
+        return Err(format!("Invalid zero timeout {}", raw));
+    }
+
+    Ok(timeout)
+}
+
+pub(super) fn parse_filter(raw: &str) -> Result<StringFilter, String> {
+    if let Some(raw) = raw.strip_prefix('!') {
+        return parse_filter(raw).map(|filter| StringFilter::Not(Box::new(filter)));
+    }
+
+    let (kind, pattern) = match raw.split_once(':') {
+        Some((kind @ ("s" | "e" | "c" | "r" | "g" | "si" | "ei" | "ci" | "ri" | "gi" | "i"), pattern)) => {
+            (kind, pattern)
+        }
+        _ => ("", raw),
+    };
+    let (kind, ignore_case) = match kind.strip_suffix('i') {
+        Some(kind) => (kind, true),
+        None => (kind, false),
+    };
+
+    if kind == "r" {
+        // The regex engine folds case itself, which lowercasing the pattern would break (\D, \W, ...)
+        return RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map(StringFilter::Regex)
+            .map_err(|err| format!("Invalid regex {pattern}: {err}"));
+    }
+
+    let lowercased;
+    let pattern = if ignore_case {
+        lowercased = pattern.to_lowercase();
+        lowercased.as_str()
     } else {
-        Ok(StringFilter::Equals(raw.into()))
+        pattern
+    };
+    let filter = match kind {
+        "s" => StringFilter::StartsWidth(pattern.into()),
+        "e" => StringFilter::EndsWidth(pattern.into()),
+        "c" => StringFilter::Contains(pattern.into()),
+        "g" => match Pattern::new(pattern) {
+            Ok(pattern) => StringFilter::Glob(pattern),
+            Err(err) => return Err(format!("Invalid glob {pattern}: {err}")),
+        },
+        _ => StringFilter::Equals(pattern.into()),
+    };
+
+    if ignore_case {
+        Ok(StringFilter::IgnoreCase(Box::new(filter)))
+    } else {
+        Ok(filter)
     }
 }
 
+/// The filter grammar of `parse_filter`, where an unprefixed value is a path compared after resolving symlinks
+pub(super) fn parse_path_filter(raw: &str) -> Result<PathFilter, String> {
+    if let Some(raw) = raw.strip_prefix('!') {
+        return parse_path_filter(raw).map(|filter| PathFilter::Not(Box::new(filter)));
+    }
+    match parse_filter(raw)? {
+        StringFilter::Equals(path) => Ok(PathFilter::Equals(PathBuf::from(path.as_ref()))),
+        filter => Ok(PathFilter::Pattern(filter)),
+    }
+}
+
+/// Hex IDs as `lsusb` prints them: <vendor>[:<product>[:<version>]], i.e. 0b05:19b6
+pub(super) fn parse_id_filter(raw: &str) -> Result<IdFilter, String> {
+    let parts = raw
+        .split(':')
+        .map(|part| u16::from_str_radix(part.trim_start_matches("0x"), 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("Invalid ID {raw}: {err}"))?;
+    match parts[..] {
+        [vendor] => Ok(IdFilter {
+            vendor,
+            product: None,
+            version: None,
+        }),
+        [vendor, product] => Ok(IdFilter {
+            vendor,
+            product: Some(product),
+            version: None,
+        }),
+        [vendor, product, version] => Ok(IdFilter {
+            vendor,
+            product: Some(product),
+            version: Some(version),
+        }),
+        _ => Err(format!("Invalid ID {raw}: expected <vendor>[:<product>[:<version>]]")),
+    }
+}
+
+pub(super) fn parse_udev_prop(raw: &str) -> Result<UdevPropFilter, String> {
+    match raw.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok(UdevPropFilter {
+            key: key.into(),
+            value: parse_filter(value)?,
+        }),
+        _ => Err(format!("Invalid udev property filter {raw}: expected <KEY>=<VALUE>")),
+    }
+}
+
+/// A kernel event type name with or without the EV_ prefix, in any case (REL, EV_SW), or the evdev name (RELATIVE)
+pub(super) fn parse_event_type(raw: &str) -> Result<EventType, String> {
+    let name = raw.to_uppercase();
+    let event_type = match name.strip_prefix("EV_").unwrap_or(&name) {
+        "SYN" => EventType::SYNCHRONIZATION,
+        "REL" => EventType::RELATIVE,
+        "ABS" => EventType::ABSOLUTE,
+        "MSC" => EventType::MISC,
+        "SW" => EventType::SWITCH,
+        "SND" => EventType::SOUND,
+        "REP" => EventType::REPEAT,
+        "FF" => EventType::FORCEFEEDBACK,
+        "PWR" => EventType::POWER,
+        "FF_STATUS" => EventType::FORCEFEEDBACKSTATUS,
+        name => EventType::from_str(name).map_err(|_| format!("Invalid event type {raw}"))?,
+    };
+    Ok(event_type)
+}
+
+/// A bus type name with or without the BUS_ prefix, in any case (usb, BUS_BLUETOOTH), or its number
+pub(super) fn parse_bus(raw: &str) -> Result<BusType, String> {
+    let number = match raw.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => raw.parse::<u16>(),
+    };
+    if let Ok(number) = number {
+        return Ok(BusType(number));
+    }
+
+    let name = raw.to_uppercase();
+    let name = if name.starts_with("BUS_") {
+        name
+    } else {
+        format!("BUS_{name}")
+    };
+    BusType::from_str(&name).map_err(|_| format!("Invalid bus type {raw}"))
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -158,4 +744,140 @@ mod tests {
         assert!(parse_key_range("0:0:1").is_ok());
         assert!(parse_key_range("1:0:1").is_err());
     }
+
+    #[test]
+    fn test_parse_timeout_range() {
+        use super::*;
+        assert_eq!(
+            parse_timeout_range("scan:0x70004:0x7001d:70").unwrap(),
+            TimeoutRange::Scan(ScanRangeTimeout {
+                range: 0x70004..=0x7001d,
+                timeout: Duration::from_millis(70),
+            })
+        );
+        assert_eq!(
+            parse_timeout_range("30:40:70").unwrap(),
+            TimeoutRange::Key(parse_key_rule("30:40:70").unwrap())
+        );
+        assert_eq!(
+            parse_timeout_range("KEY_Q..KEY_P:70ms").unwrap(),
+            TimeoutRange::Key(parse_key_rule("KEY_Q..KEY_P:70ms").unwrap())
+        );
+
+        assert!(parse_timeout_range("scan:30:40:0").is_err());
+        assert!(parse_timeout_range("scan:40:30:70").is_err());
+        assert!(parse_timeout_range("scan:0x70004:70").is_err());
+        assert!(parse_timeout_range("scan:0xzz:0x7001d:70").is_err());
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        use super::*;
+        assert_eq!(parse_timeout("30").unwrap(), Duration::from_millis(30));
+        assert_eq!(parse_timeout("30ms").unwrap(), Duration::from_millis(30));
+        assert_eq!(parse_timeout("500us").unwrap(), Duration::from_micros(500));
+        assert_eq!(parse_timeout("2s").unwrap(), Duration::from_secs(2));
+        assert!(parse_timeout("0s").is_err());
+        assert!(parse_timeout("ms").is_err());
+        assert!(parse_timeout("30m").is_err());
+        assert!(parse_timeout("0").is_err());
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("abc").is_err());
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        use super::*;
+        let matches = |filter: &str, raw: &str| parse_filter(filter).unwrap().matches(raw);
+
+        assert!(matches("Asus Keyboard", "Asus Keyboard"));
+        assert!(matches("usb-0000:04:00.3-3/input2", "usb-0000:04:00.3-3/input2"));
+        assert!(matches("s:Asus", "Asus Keyboard"));
+        assert!(!matches("s:asus", "Asus Keyboard"));
+        assert!(matches("si:asus", "Asus Keyboard"));
+        assert!(matches("i:ASUS KEYBOARD", "Asus Keyboard"));
+        assert!(matches("!c:Mouse", "Asus Keyboard"));
+        assert!(!matches("!ci:mouse", "Logitech Mouse"));
+        assert!(matches(r"r:^Asus (Keyboard|WMI)$", "Asus Keyboard"));
+        assert!(matches(r"ri:\bKEYBOARD$", "Asus Keyboard"));
+        assert!(matches("g:Asus*", "Asus Keyboard"));
+        assert!(!matches("g:Keyboard", "Asus Keyboard"));
+        assert!(matches("gi:*KEY?OARD", "Asus Keyboard"));
+
+        assert!(parse_filter("r:(").is_err());
+        assert!(parse_filter("g:[").is_err());
+    }
+
+    #[test]
+    fn test_parse_id_filter() {
+        use super::*;
+        let input_id = InputId::new(BusType::BUS_USB, 0x0b05, 0x19b6, 0x0110);
+
+        assert!(parse_id_filter("0b05").unwrap().matches(&input_id));
+        assert!(parse_id_filter("0b05:19b6").unwrap().matches(&input_id));
+        assert!(parse_id_filter("0x0b05:0x19b6:110").unwrap().matches(&input_id));
+        assert!(!parse_id_filter("0b05:19b7").unwrap().matches(&input_id));
+        assert!(!parse_id_filter("0b05:19b6:111").unwrap().matches(&input_id));
+        assert!(parse_id_filter("0b05:").is_err());
+        assert!(parse_id_filter("0b05:19b6:110:1").is_err());
+
+        assert_eq!(parse_bus("usb").unwrap(), BusType::BUS_USB);
+        assert_eq!(parse_bus("BUS_BLUETOOTH").unwrap(), BusType::BUS_BLUETOOTH);
+        assert_eq!(parse_bus("0x19").unwrap(), BusType::BUS_HOST);
+        assert!(parse_bus("carrier-pigeon").is_err());
+    }
+
+    #[test]
+    fn test_parse_path_filter() {
+        use super::*;
+        let dir = std::env::temp_dir().join(format!("dechat-rs-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let node = dir.join("event4");
+        let alias = dir.join("usb-Asus_Keyboard-event-kbd");
+        std::fs::write(&node, "").unwrap();
+        std::os::unix::fs::symlink(&node, &alias).unwrap();
+
+        let matches = |filter: &str, path: &Path| parse_path_filter(filter).unwrap().matches(path);
+        assert!(matches(node.to_str().unwrap(), &node));
+        assert!(matches(alias.to_str().unwrap(), &node));
+        assert!(!matches(&format!("!{}", alias.display()), &node));
+        assert!(matches("e:/event4", &node));
+        assert!(!matches("e:/event5", &node));
+        assert!(matches("!e:/event5", &node));
+        assert!(!matches(dir.join("event5").to_str().unwrap(), &node));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_event_type() {
+        use super::*;
+        assert_eq!(parse_event_type("REL").unwrap(), EventType::RELATIVE);
+        assert_eq!(parse_event_type("ev_sw").unwrap(), EventType::SWITCH);
+        assert_eq!(parse_event_type("KEY").unwrap(), EventType::KEY);
+        assert_eq!(parse_event_type("LED").unwrap(), EventType::LED);
+        assert_eq!(parse_event_type("RELATIVE").unwrap(), EventType::RELATIVE);
+        assert!(parse_event_type("SMELL").is_err());
+    }
+
+    #[test]
+    fn test_parse_udev_prop() {
+        use super::*;
+        let properties = HashMap::from([
+            ("ID_INPUT_KEYBOARD".to_string(), "1".to_string()),
+            (
+                "ID_SERIAL".to_string(),
+                "ASUSTeK_Computer_Inc._N-KEY_Device".to_string(),
+            ),
+        ]);
+        let matches = |filter: &str| parse_udev_prop(filter).unwrap().matches(&properties);
+
+        assert!(matches("ID_INPUT_KEYBOARD=1"));
+        assert!(matches("ID_SERIAL=c:N-KEY"));
+        assert!(!matches("ID_INPUT_MOUSE=1"));
+        assert!(matches("ID_INPUT_MOUSE=!1"));
+        assert!(matches("ID_INPUT_MOUSE="));
+        assert!(parse_udev_prop("ID_INPUT").is_err());
+        assert!(parse_udev_prop("=1").is_err());
+    }
 }
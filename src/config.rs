@@ -0,0 +1,503 @@
+use std::ffi::OsStr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, info};
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use serde::Deserialize;
+
+use crate::cmd::{
+    parse_bus, parse_event_type, parse_filter, parse_id_filter, parse_key_range, parse_path_filter,
+    parse_timeout_range, parse_udev_prop, DeviceFilters, KeyRangeTimeout, TimeoutRange,
+};
+use crate::device_wrapper::DeviceWrapper;
+use crate::error::DechatError;
+use crate::key_spec::parse_key_spec;
+
+pub(super) const DEFAULT_CONFIG_PATH: &str = "/etc/dechat-rs/config.toml";
+pub(super) const DEFAULT_GRAB_TIMEOUT: Duration = Duration::from_millis(3000);
+
+/// The rules a key filter applies; they can be replaced while the device stays grabbed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct FilterSettings {
+    pub(super) timeouts: Vec<TimeoutRange>,
+    pub(super) switch_timeouts: Vec<KeyRangeTimeout>,
+    pub(super) wheel_reversal: Option<Duration>,
+    pub(super) grab_timeout: Option<Duration>,
+}
+
+impl FilterSettings {
+    /// Rules of `self` win over overlapping rules of the fallback, however specific those are: the key rules of the
+    /// fallback go to the layers below, and the other rules come after; unset values are taken from it
+    fn or(self, fallback: &FilterSettings) -> Self {
+        let below = self
+            .timeouts
+            .iter()
+            .filter_map(|timeout_range| match timeout_range {
+                TimeoutRange::Key(key_rule) => Some(key_rule.layer + 1),
+                TimeoutRange::Scan(_) => None,
+            })
+            .max()
+            .unwrap_or(1);
+        let mut fallback = fallback.clone();
+        for timeout_range in &mut fallback.timeouts {
+            if let TimeoutRange::Key(key_rule) = timeout_range {
+                key_rule.layer += below;
+            }
+        }
+        self.merge(&fallback)
+    }
+
+    /// Rules of `self` come first, so they win over equally specific rules of the fallback in the same layer
+    fn merge(mut self, fallback: &FilterSettings) -> Self {
+        self.timeouts.extend(fallback.timeouts.iter().cloned());
+        self.switch_timeouts.extend(fallback.switch_timeouts.iter().cloned());
+        self.wheel_reversal = self.wheel_reversal.or(fallback.wheel_reversal);
+        self.grab_timeout = self.grab_timeout.or(fallback.grab_timeout);
+        self
+    }
+}
+
+/// A `[[device]]` section: the settings for the devices matching all its filters
+#[derive(Debug, Clone)]
+pub(super) struct Profile {
+    pub(super) filters: DeviceFilters,
+    pub(super) settings: FilterSettings,
+    pub(super) virtual_name: Option<String>,
+}
+
+/// The config file uses the same formats as the command line flags, i.e. `timeouts = ["0:1000:70"]`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    timeouts: Vec<String>,
+    switch_timeouts: Vec<String>,
+    /// Milliseconds
+    wheel_reversal: Option<u64>,
+    /// Milliseconds
+    grab_timeout: Option<u64>,
+    device: Vec<ProfileFile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProfileFile {
+    name: OneOrMany,
+    path: OneOrMany,
+    physical_path: OneOrMany,
+    bus: OneOrMany,
+    id: OneOrMany,
+    uniq: OneOrMany,
+    has_key: OneOrMany,
+    has_event_type: OneOrMany,
+    udev_prop: OneOrMany,
+    auto_keyboards: bool,
+    timeouts: Vec<String>,
+    switch_timeouts: Vec<String>,
+    wheel_reversal: Option<u64>,
+    grab_timeout: Option<u64>,
+    virtual_name: Option<String>,
+}
+
+/// Filters are repeatable on the command line, so a single filter or a list of them is accepted
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for OneOrMany {
+    fn default() -> Self {
+        OneOrMany::Many(vec![])
+    }
+}
+
+impl OneOrMany {
+    fn iter(&self) -> impl Iterator<Item = &str> {
+        match self {
+            OneOrMany::One(value) => std::slice::from_ref(value).iter(),
+            OneOrMany::Many(values) => values.iter(),
+        }
+        .map(String::as_str)
+    }
+}
+
+/// Points at the config file and its drop-in directory (`config.toml` -> `config.d/*.toml`);
+/// they are read again on every reload
+pub(super) struct Config {
+    cli: FilterSettings,
+    path: PathBuf,
+    explicit: bool,
+}
+
+impl Config {
+    /// Without an explicit path, the system default is used if it exists
+    pub(super) fn new(cli: FilterSettings, path: Option<PathBuf>) -> Self {
+        let explicit = path.is_some();
+        let path = path.unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+        Self { cli, path, explicit }
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(super) fn drop_in_dir(&self) -> PathBuf {
+        self.path.with_extension("d")
+    }
+
+    pub(super) fn load(&self) -> anyhow::Result<LoadedConfig> {
+        self.read().map_err(|err| DechatError::InvalidConfig(err).into())
+    }
+
+    fn read(&self) -> anyhow::Result<LoadedConfig> {
+        let mut loaded = LoadedConfig {
+            cli: self.cli.clone(),
+            global: FilterSettings::default(),
+            profiles: vec![],
+        };
+
+        if self.explicit || self.path.exists() {
+            loaded.apply(&self.path)?;
+        }
+
+        let drop_in_dir = self.drop_in_dir();
+        if drop_in_dir.is_dir() {
+            let mut drop_ins = std::fs::read_dir(&drop_in_dir)
+                .with_context(|| format!("Failed to read {}", drop_in_dir.display()))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            drop_ins.retain(|path| path.extension() == Some(OsStr::new("toml")));
+            drop_ins.sort_unstable();
+            for path in drop_ins {
+                loaded.apply(&path)?;
+            }
+        }
+
+        Ok(loaded)
+    }
+}
+
+/// The parsed config files together with the command line settings, which win over them
+pub(super) struct LoadedConfig {
+    cli: FilterSettings,
+    global: FilterSettings,
+    profiles: Vec<Profile>,
+}
+
+impl LoadedConfig {
+    /// Later files win: their rules come first in the same layer, and their profiles are tried first
+    fn apply(&mut self, path: &Path) -> anyhow::Result<()> {
+        let raw = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let (global, mut profiles) =
+            parse_config(&raw).with_context(|| format!("Invalid config {}", path.display()))?;
+        info!(
+            "Loaded the config {} with {} device profile(s)",
+            path.display(),
+            profiles.len()
+        );
+
+        self.global = global.merge(&self.global);
+        profiles.append(&mut self.profiles);
+        self.profiles = profiles;
+        Ok(())
+    }
+
+    pub(super) fn profiles(&self) -> &[Profile] {
+        &self.profiles
+    }
+
+    /// The first profile whose filters all match the device
+    pub(super) fn profile_for(&self, device_wrapper: &DeviceWrapper) -> Option<&Profile> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.filters.matches(device_wrapper))
+    }
+
+    /// The settings for devices matching no profile
+    pub(super) fn default_settings(&self) -> FilterSettings {
+        self.cli.clone().or(&self.global)
+    }
+
+    /// The command line rules, then the rules of the matching profile, then the global rules of the files
+    pub(super) fn settings_for(&self, device_wrapper: &DeviceWrapper) -> FilterSettings {
+        let settings = match self.profile_for(device_wrapper) {
+            Some(profile) => {
+                debug!(
+                    "Applying the profile {:?} to {}",
+                    profile.filters,
+                    device_wrapper.name()
+                );
+                self.cli.clone().or(&profile.settings)
+            }
+            None => self.cli.clone(),
+        };
+        settings.or(&self.global)
+    }
+}
+
+fn parse_settings(
+    timeouts: &[String],
+    switch_timeouts: &[String],
+    wheel_reversal: Option<u64>,
+    grab_timeout: Option<u64>,
+) -> anyhow::Result<FilterSettings> {
+    let timeouts = timeouts
+        .iter()
+        .map(|raw| parse_timeout_range(raw))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(anyhow::Error::msg)?;
+    let switch_timeouts = switch_timeouts
+        .iter()
+        .map(|raw| parse_key_range(raw))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(anyhow::Error::msg)?;
+
+    Ok(FilterSettings {
+        timeouts,
+        switch_timeouts,
+        wheel_reversal: parse_millis("wheel_reversal", wheel_reversal)?,
+        grab_timeout: parse_millis("grab_timeout", grab_timeout)?,
+    })
+}
+
+fn parse_millis(field: &str, value: Option<u64>) -> anyhow::Result<Option<Duration>> {
+    match value {
+        Some(0) => anyhow::bail!("Invalid zero timeout {field}"),
+        Some(value) => Ok(Some(Duration::from_millis(value))),
+        None => Ok(None),
+    }
+}
+
+fn parse_filters<T>(values: &OneOrMany, parser: fn(&str) -> Result<T, String>) -> anyhow::Result<Vec<T>> {
+    values
+        .iter()
+        .map(parser)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(anyhow::Error::msg)
+}
+
+fn parse_config(raw: &str) -> anyhow::Result<(FilterSettings, Vec<Profile>)> {
+    let file: ConfigFile = toml::from_str(raw)?;
+
+    let global = parse_settings(
+        &file.timeouts,
+        &file.switch_timeouts,
+        file.wheel_reversal,
+        file.grab_timeout,
+    )?;
+
+    let mut profiles = vec![];
+    for (index, profile) in file.device.into_iter().enumerate() {
+        let filters = DeviceFilters {
+            name: parse_filters(&profile.name, parse_filter)?,
+            path: parse_filters(&profile.path, parse_path_filter)?,
+            physical_path: parse_filters(&profile.physical_path, parse_filter)?,
+            bus: parse_filters(&profile.bus, parse_bus)?,
+            id: parse_filters(&profile.id, parse_id_filter)?,
+            uniq: parse_filters(&profile.uniq, parse_filter)?,
+            has_key: parse_filters(&profile.has_key, parse_key_spec)?,
+            has_event_type: parse_filters(&profile.has_event_type, parse_event_type)?,
+            udev_prop: parse_filters(&profile.udev_prop, parse_udev_prop)?,
+            auto_keyboards: profile.auto_keyboards,
+        };
+        if filters.is_empty() {
+            anyhow::bail!("The device profile #{index} has no filters and would match every device");
+        }
+        let settings = parse_settings(
+            &profile.timeouts,
+            &profile.switch_timeouts,
+            profile.wheel_reversal,
+            profile.grab_timeout,
+        )
+        .with_context(|| format!("Invalid device profile #{index}"))?;
+
+        profiles.push(Profile {
+            filters,
+            settings,
+            virtual_name: profile.virtual_name,
+        });
+    }
+
+    Ok((global, profiles))
+}
+
+/// Watches the directories of the config file and its drop-ins, since editors usually replace the file instead of
+/// writing it in place
+pub(super) struct ConfigWatcher {
+    inotify: Inotify,
+}
+
+impl ConfigWatcher {
+    pub(super) fn new(config: &Config) -> anyhow::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+
+        let dir = match config.path().parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut watched = 0;
+        for dir in [dir.to_path_buf(), config.drop_in_dir()] {
+            if !dir.is_dir() {
+                continue;
+            }
+            inotify.add_watch(
+                &dir,
+                AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_DELETE,
+            )?;
+            watched += 1;
+        }
+        if watched == 0 {
+            anyhow::bail!("Can't watch {}: the directory does not exist", config.path().display());
+        }
+
+        Ok(Self { inotify })
+    }
+
+    /// Drains pending notifications and tells whether a config file was among them
+    pub(super) fn changed(&self) -> anyhow::Result<bool> {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(events
+            .into_iter()
+            .filter_map(|event| event.name)
+            .any(|name| Path::new(&name).extension() == Some(OsStr::new("toml"))))
+    }
+}
+
+impl AsRawFd for ConfigWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::ScanRangeTimeout;
+    use crate::key_spec::resolve_key_rules;
+    use evdev::KeyCode;
+
+    #[test]
+    fn test_parse_config() {
+        let (global, profiles) = parse_config(
+            r#"
+            timeouts = ["0:1000:70", "scan:0x70004:0x7001d:40"]
+            switch_timeouts = ["0:1:500"]
+            wheel_reversal = 150
+
+            [[device]]
+            name = "s:Asus Keyboard"
+            physical_path = ["usb-0000:04:00.3-3/input2"]
+            timeouts = ["14:14:100"]
+            grab_timeout = 5000
+            virtual_name = "Asus"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            global,
+            FilterSettings {
+                timeouts: vec![
+                    parse_timeout_range("0:1000:70").unwrap(),
+                    TimeoutRange::Scan(ScanRangeTimeout {
+                        range: 0x70004..=0x7001d,
+                        timeout: Duration::from_millis(40),
+                    }),
+                ],
+                switch_timeouts: vec![KeyRangeTimeout {
+                    range: 0..=1,
+                    timeout: Duration::from_millis(500),
+                }],
+                wheel_reversal: Some(Duration::from_millis(150)),
+                grab_timeout: None,
+            }
+        );
+
+        assert_eq!(profiles.len(), 1);
+        let profile = &profiles[0];
+        assert_eq!(profile.filters.name.len(), 1);
+        assert!(profile.filters.name[0].matches("Asus Keyboard"));
+        assert!(profile.filters.physical_path[0].matches("usb-0000:04:00.3-3/input2"));
+        assert!(profile.filters.path.is_empty());
+        assert_eq!(profile.settings.grab_timeout, Some(Duration::from_millis(5000)));
+        assert_eq!(profile.virtual_name.as_deref(), Some("Asus"));
+
+        let (global, profiles) = parse_config("").unwrap();
+        assert_eq!(global, FilterSettings::default());
+        assert!(profiles.is_empty());
+
+        assert!(parse_config(r#"timeouts = ["1:0:70"]"#).is_err());
+        assert!(parse_config("wheel_reversal = 0").is_err());
+        assert!(parse_config("unknown = 1").is_err());
+        assert!(parse_config("[[device]]\ntimeouts = [\"0:1:70\"]").is_err());
+    }
+
+    #[test]
+    fn test_settings_precedence() {
+        let cli = FilterSettings {
+            timeouts: vec![parse_timeout_range("KEY_ESC:10").unwrap()],
+            grab_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let file = FilterSettings {
+            timeouts: vec![parse_timeout_range("0:10:70").unwrap()],
+            wheel_reversal: Some(Duration::from_millis(150)),
+            grab_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        let settings = cli.clone().or(&file);
+        assert_eq!(settings.timeouts.len(), 2);
+        assert_eq!(settings.timeouts[0], cli.timeouts[0]);
+        assert_eq!(settings.wheel_reversal, Some(Duration::from_millis(150)));
+        assert_eq!(settings.grab_timeout, Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_cli_rules_win_over_file_rules() {
+        let key_rules = |settings: &FilterSettings| {
+            settings
+                .timeouts
+                .iter()
+                .filter_map(|timeout_range| match timeout_range {
+                    TimeoutRange::Key(key_rule) => Some(key_rule.clone()),
+                    TimeoutRange::Scan(_) => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let settings = |raw: &[&str]| FilterSettings {
+            timeouts: raw.iter().map(|raw| parse_timeout_range(raw).unwrap()).collect(),
+            ..Default::default()
+        };
+        let cli = settings(&["all:30"]);
+        let profile = settings(&["KEY_ENTER:10"]);
+        let global = settings(&["all:70", "KEY_SPACE:20"]);
+
+        let rules = key_rules(&cli.clone().or(&global));
+        let resolutions = resolve_key_rules(&rules, KeyCode::KEY_SPACE.0 as usize + 1);
+        let applied = |key_code: KeyCode| {
+            let rule = resolutions[key_code.0 as usize].as_ref().unwrap().rule;
+            rules[rule].timeout
+        };
+        assert_eq!(applied(KeyCode::KEY_SPACE), Duration::from_millis(30));
+        assert_eq!(applied(KeyCode::KEY_ESC), Duration::from_millis(30));
+
+        // The profile wins over the global rules the same way, and specificity still counts within a layer
+        let rules = key_rules(&settings(&[]).or(&profile).or(&global));
+        let resolutions = resolve_key_rules(&rules, KeyCode::KEY_SPACE.0 as usize + 1);
+        let applied = |key_code: KeyCode| resolutions[key_code.0 as usize].as_ref().map(|r| rules[r.rule].timeout);
+        assert_eq!(applied(KeyCode::KEY_ENTER), Some(Duration::from_millis(10)));
+        assert_eq!(applied(KeyCode::KEY_SPACE), Some(Duration::from_millis(20)));
+        assert_eq!(applied(KeyCode::KEY_A), Some(Duration::from_millis(70)));
+    }
+}
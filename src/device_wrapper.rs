@@ -1,11 +1,26 @@
-use crate::cmd::KeyRangeTimeout;
+use crate::config::FilterSettings;
+use crate::error::{open_device, uinput_error};
 use crate::key_filter::KeyFilter;
+use crate::key_spec::alphanumeric_codes;
 use crate::traits::DeviceExt;
 use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
-use evdev::{AttributeSet, Device, KeyCode};
+use evdev::{AttributeSet, Device, KeyCode, RelativeAxisCode, SwitchCode};
 use log::info;
+use nix::errno::Errno;
 use std::cmp::Ordering;
-use std::path::PathBuf;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+
+/// The physical path of the virtual devices, telling them apart from real devices whatever their name
+const VIRTUAL_PHYS: &CStr = c"dechat-rs";
+
+/// Devices with a full set of keys that must not be de-chattered. Power buttons, the video bus and ALSA jacks need
+/// no entry, they lack the letters and digits. YubiKeys type one-time passwords as a keyboard, fast enough and with
+/// enough repeated characters for a de-chatter timeout to eat some
+const NOT_KEYBOARD_NAMES: [&str; 1] = ["YubiKey"];
+
+/// Directories of the stable udev symlinks to the event nodes
+const ALIAS_DIRS: [&str; 2] = ["/dev/input/by-id", "/dev/input/by-path"];
 
 pub(super) struct DeviceWrapper {
     pub(super) path: PathBuf,
@@ -17,6 +32,40 @@ impl DeviceWrapper {
         self.device.name().unwrap_or("Unnamed device")
     }
 
+    /// Whether this is a virtual device created by dechat-rs
+    pub(super) fn is_dechat_virtual(&self) -> bool {
+        self.device.physical_path().map(str::as_bytes) == Some(VIRTUAL_PHYS.to_bytes())
+    }
+
+    /// Whether the device has all letters and digits, and is neither a YubiKey nor a virtual device of dechat-rs
+    pub(super) fn is_keyboard(&self) -> bool {
+        if self.is_dechat_virtual() || NOT_KEYBOARD_NAMES.iter().any(|name| self.name().contains(name)) {
+            return false;
+        }
+        self.device
+            .supported_keys()
+            .is_some_and(|keys| alphanumeric_codes().all(|code| keys.contains(KeyCode(code))))
+    }
+
+    /// The `/dev/input/by-id` and `/dev/input/by-path` symlinks pointing to the event node
+    pub(super) fn aliases(&self) -> Vec<PathBuf> {
+        aliases_of(&self.path)
+    }
+
+    /// Whether another process holds a grab on the device, found out by grabbing and releasing it right away;
+    /// None if that is not possible
+    pub(super) fn is_grabbed_elsewhere(&self) -> Option<bool> {
+        let mut device = Device::open(&self.path).ok()?;
+        match device.grab() {
+            Ok(()) => {
+                device.ungrab().ok()?;
+                Some(false)
+            }
+            Err(err) if err.raw_os_error() == Some(Errno::EBUSY as i32) => Some(true),
+            Err(_) => None,
+        }
+    }
+
     fn get_ordering_key(&self) -> (u16, u16, u16, u16, &str, &PathBuf) {
         let id = self.device.input_id();
         (
@@ -29,39 +78,72 @@ impl DeviceWrapper {
         )
     }
 
-    pub(super) fn create_fake_keyboard(&self) -> std::io::Result<VirtualDevice> {
+    /// Creates a virtual device advertising the union of the capabilities of all given devices.
+    /// It is named after them unless a name is given
+    pub(super) fn create_fake_keyboard(
+        wrappers: &[DeviceWrapper],
+        name: Option<&str>,
+    ) -> anyhow::Result<VirtualDevice> {
         let mut keys = AttributeSet::<KeyCode>::new();
-        for supported_key in self
-            .device
-            .supported_keys()
-            .iter()
-            .flat_map(|attribute_set| attribute_set.iter())
-        {
-            keys.insert(supported_key);
+        let mut relative_axes = AttributeSet::<RelativeAxisCode>::new();
+        let mut switches = AttributeSet::<SwitchCode>::new();
+        let mut names = Vec::<&str>::new();
+
+        for wrapper in wrappers {
+            for supported_key in wrapper.device.supported_keys().iter().flat_map(|set| set.iter()) {
+                keys.insert(supported_key);
+            }
+            for supported_axis in wrapper
+                .device
+                .supported_relative_axes()
+                .iter()
+                .flat_map(|set| set.iter())
+            {
+                relative_axes.insert(supported_axis);
+            }
+            for supported_switch in wrapper.device.supported_switches().iter().flat_map(|set| set.iter()) {
+                switches.insert(supported_switch);
+            }
+            if !names.contains(&wrapper.name()) {
+                names.push(wrapper.name());
+            }
+        }
+
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => format!("De-chattered Keyboard: {}", names.join(" + ")),
+        };
+        let mut builder = VirtualDeviceBuilder::new()
+            .map_err(uinput_error)?
+            .name(&name)
+            .with_phys(VIRTUAL_PHYS)?
+            .with_keys(&keys)?;
+
+        // Mice expose the wheel as relative axes; keyboards usually have none
+        if relative_axes.iter().next().is_some() {
+            builder = builder.with_relative_axes(&relative_axes)?;
         }
 
-        let fake_keyboard = VirtualDeviceBuilder::new()?
-            .name(&format!("De-chattered Keyboard: {}", self.name()))
-            .with_keys(&keys)?
-            .build()
-            .unwrap();
+        // Lid and tablet-mode switches must be advertised to be debounced
+        if switches.iter().next().is_some() {
+            builder = builder.with_switches(&switches)?;
+        }
+
+        let mut fake_keyboard = builder.build().map_err(uinput_error)?;
+
+        let paths = fake_keyboard.paths()?;
+        info!("Created a fake keyboard; it is available as {:?}", paths);
 
         Ok(fake_keyboard)
     }
 
-    pub(super) fn build_key_filter(
-        self,
-        timeouts: Vec<KeyRangeTimeout>,
-        skip_first: bool,
-    ) -> anyhow::Result<KeyFilter> {
+    pub(super) fn build_key_filter(self, settings: &FilterSettings) -> anyhow::Result<KeyFilter> {
         info!("Picked the original keyboard: {}; path: {:?}", self.name(), self.path);
 
-        let mut fake_keyboard = self.create_fake_keyboard()?;
-
-        let paths = fake_keyboard.paths()?;
-        info!("Created a fake keyboard; it is available as {:?}", paths);
+        // The filter reads the raw stream to notice dropped events itself
+        let orig_keyboard = open_device(&self.path)?;
 
-        Ok(KeyFilter::new(timeouts, self.device, fake_keyboard, skip_first))
+        Ok(KeyFilter::new(settings, orig_keyboard))
     }
 
     pub(super) fn list_wrapped_divices() -> Vec<DeviceWrapper> {
@@ -71,6 +153,22 @@ impl DeviceWrapper {
     }
 }
 
+/// The stable udev symlinks resolving to the same node as the path
+pub(super) fn aliases_of(path: &Path) -> Vec<PathBuf> {
+    let Ok(target) = path.canonicalize() else {
+        return vec![];
+    };
+    let mut aliases = ALIAS_DIRS
+        .iter()
+        .filter_map(|dir| Path::new(dir).read_dir().ok())
+        .flat_map(|entries| entries.flatten())
+        .map(|entry| entry.path())
+        .filter(|alias| alias.canonicalize().is_ok_and(|alias_target| alias_target == target))
+        .collect::<Vec<_>>();
+    aliases.sort_unstable();
+    aliases
+}
+
 impl From<(PathBuf, Device)> for DeviceWrapper {
     fn from((path, device): (PathBuf, Device)) -> Self {
         Self { path, device }
@@ -1,6 +1,7 @@
 use colored::Colorize;
+use serde::Serialize;
 
-use crate::cmd::Command;
+use crate::cmd::{Command, OutputFormat};
 use crate::device_wrapper::DeviceWrapper;
 
 trait Colorizer<T> {
@@ -23,100 +24,618 @@ pub(super) struct DisplayOpts {
     name: bool,
     id: bool,
     keys: bool,
+    capabilities: bool,
+    repeat: bool,
+    uniq: bool,
+    aliases: bool,
+    status: bool,
+    format: OutputFormat,
 }
 
-impl TryFrom<Command> for DisplayOpts {
+impl DisplayOpts {
+    /// The CSV and table columns, in the order of `DeviceRecord::cells`
+    fn columns(&self) -> Vec<&'static str> {
+        let mut columns = vec!["index"];
+        if self.path {
+            columns.push("path");
+        }
+        if self.physical_path {
+            columns.push("physical_path");
+        }
+        if self.name {
+            columns.push("name");
+        }
+        if self.id {
+            columns.extend(["bus", "bus_id", "vendor", "product", "version"]);
+        }
+        if self.uniq {
+            columns.push("uniq");
+        }
+        if self.repeat {
+            columns.extend(["repeat_delay_ms", "repeat_period_ms"]);
+        }
+        if self.status {
+            columns.extend(["grabbed", "dechat_virtual"]);
+        }
+        if self.keys {
+            columns.push("keys");
+        }
+        if self.capabilities {
+            columns.extend(["properties", "event_types", "leds", "switches"]);
+        }
+        if self.aliases {
+            columns.push("aliases");
+        }
+        columns
+    }
+
+    /// The CSV columns of `list --watch`: what happened to the device comes first
+    fn watch_columns(&self) -> Vec<&'static str> {
+        ["event"].into_iter().chain(self.columns()).collect()
+    }
+}
+
+impl TryFrom<&Command> for DisplayOpts {
     type Error = anyhow::Error;
 
-    fn try_from(value: Command) -> Result<Self, Self::Error> {
-        match value {
+    fn try_from(value: &Command) -> Result<Self, Self::Error> {
+        match *value {
             Command::List {
                 path,
                 physical_path,
                 name,
                 id,
                 keys,
+                capabilities,
+                repeat,
+                uniq,
+                aliases,
+                status,
                 all,
+                format,
+                ..
             } => Ok(Self {
                 path: path || all,
                 physical_path: physical_path || all,
                 name: name || all,
                 id: id || all,
                 keys: keys || all,
+                capabilities: capabilities || all,
+                repeat: repeat || all,
+                uniq: uniq || all,
+                aliases: aliases || all,
+                status,
+                format,
             }),
-            Command::DeChatter { .. } => {
+            Command::DeChatter { .. } | Command::CheckConfig { .. } | Command::Identify { .. } => {
                 anyhow::bail!("Can't construct DisplayOpts from anything byt List")
             }
         }
     }
 }
 
+/// What happened to a device, for `list --watch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum DeviceEvent {
+    /// Already there when the watch started
+    Present,
+    Added,
+    Removed,
+}
+
 pub(super) struct DevicePrinter<'a> {
+    event: Option<DeviceEvent>,
+    index: usize,
     wrapper: &'a DeviceWrapper,
     display_opts: &'a DisplayOpts,
 }
 
 impl<'a> DevicePrinter<'a> {
-    pub(super) fn new(wrapper: &'a DeviceWrapper, display_opts: &'a DisplayOpts) -> DevicePrinter<'a> {
-        Self { wrapper, display_opts }
+    pub(super) fn new(index: usize, wrapper: &'a DeviceWrapper, display_opts: &'a DisplayOpts) -> DevicePrinter<'a> {
+        Self {
+            event: None,
+            index,
+            wrapper,
+            display_opts,
+        }
+    }
+
+    pub(super) fn with_event(mut self, event: DeviceEvent) -> Self {
+        self.event = Some(event);
+        self
     }
 
     pub(super) fn print(&self, f: &mut impl std::io::Write) -> std::io::Result<()> {
-        let device = &self.wrapper.device;
-        let path = &self.wrapper.path;
-        if self.display_opts.path {
-            f.write_kv_pair("path", path.display())?;
+        let record = DeviceRecord::new(self.index, self.wrapper, self.display_opts);
+        match self.event {
+            Some(DeviceEvent::Added) => write!(f, "{} ", "added".green())?,
+            Some(DeviceEvent::Removed) => write!(f, "{} ", "removed".red())?,
+            Some(DeviceEvent::Present) | None => {}
         }
+        f.write_kv_pair("index", record.index)?;
 
-        if self.display_opts.physical_path {
-            f.write_kv_pair(" physical_path", device.physical_path().unwrap_or("?"))?;
+        if let Some(path) = &record.path {
+            f.write_kv_pair(" path", path)?;
         }
 
-        if self.display_opts.name {
-            f.write_kv_pair(" name", device.name().unwrap_or("?"))?;
+        if let Some(physical_path) = record.physical_path {
+            f.write_kv_pair(" physical_path", physical_path.unwrap_or("?"))?;
         }
 
-        if self.display_opts.id {
-            let input_id = device.input_id();
-            f.write_kv_pair(" bus", input_id.bus_type())?;
-            f.write_kv_pair(" bus_id", format!("{:#x}", input_id.bus_type().0))?;
-            f.write_kv_pair(" vendor", format!("{:#x}", input_id.vendor()))?;
-            f.write_kv_pair(" product", format!("{:#x}", input_id.product()))?;
-            f.write_kv_pair(" version", format!("{:#x}", input_id.version()))?;
+        if let Some(name) = record.name {
+            f.write_kv_pair(" name", name.unwrap_or("?"))?;
         }
 
-        if self.display_opts.keys {
-            let mut parts = device
-                .supported_keys()
-                .into_iter()
-                .flat_map(|attribute_set| attribute_set.iter())
-                .peekable();
+        if let Some(id) = &record.id {
+            f.write_kv_pair(" bus", &id.bus)?;
+            f.write_kv_pair(" bus_id", format!("{:#x}", id.bus_id))?;
+            f.write_kv_pair(" vendor", format!("{:#x}", id.vendor))?;
+            f.write_kv_pair(" product", format!("{:#x}", id.product))?;
+            f.write_kv_pair(" version", format!("{:#x}", id.version))?;
+        }
 
-            write!(f, "\n\t{}: ", "Keys".magenta())?;
+        if let Some(uniq) = record.uniq {
+            f.write_kv_pair(" uniq", uniq.unwrap_or("?"))?;
+        }
 
-            let has_keys = parts.peek().is_some();
-            if !has_keys {
-                writeln!(f, "{}", "None".red())?;
+        if let Some(repeat) = &record.repeat {
+            match repeat {
+                Some(repeat) => {
+                    f.write_kv_pair(" repeat_delay_ms", repeat.delay_ms)?;
+                    f.write_kv_pair(" repeat_period_ms", repeat.period_ms)?;
+                }
+                None => f.write_kv_pair(" repeat", "none")?,
             }
+        }
+
+        if let Some(grabbed) = record.grabbed {
+            f.write_kv_pair(
+                " grabbed",
+                grabbed.map_or("?", |grabbed| if grabbed { "yes" } else { "no" }),
+            )?;
+        }
+
+        if let Some(dechat_virtual) = record.dechat_virtual {
+            f.write_kv_pair(" dechat_virtual", if dechat_virtual { "yes" } else { "no" })?;
+        }
+
+        writeln!(f)?;
+
+        if let Some(keys) = &record.keys {
+            let keys = keys
+                .iter()
+                .map(|key| format!("{}={}", key.name.as_str().bold().blue(), key.code.to_string().cyan()));
+            write_section(f, "Keys", keys)?;
+        }
+
+        if let Some(properties) = &record.properties {
+            write_section(f, "Properties", properties.iter().map(|name| name.blue().to_string()))?;
+        }
+        if let Some(event_types) = &record.event_types {
+            write_section(f, "Event types", event_types.iter().map(|name| name.blue().to_string()))?;
+        }
+        if let Some(leds) = &record.leds {
+            write_section(f, "LEDs", leds.iter().map(|name| name.blue().to_string()))?;
+        }
+        if let Some(switches) = &record.switches {
+            write_section(f, "Switches", switches.iter().map(|name| name.blue().to_string()))?;
+        }
+
+        if let Some(aliases) = &record.aliases {
+            write_section(f, "Aliases", aliases.iter().map(|alias| alias.cyan().to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes an indented, comma-separated line, or None if there are no items
+fn write_section(f: &mut impl std::io::Write, label: &str, items: impl Iterator<Item = String>) -> std::io::Result<()> {
+    let items = items.collect::<Vec<_>>();
+    write!(f, "\t{}: ", label.magenta())?;
+    if items.is_empty() {
+        writeln!(f, "{}", "None".red())
+    } else {
+        writeln!(f, "{}", items.join(", "))
+    }
+}
+
+#[derive(Serialize)]
+struct KeyRecord {
+    name: String,
+    code: u16,
+}
+
+#[derive(Serialize)]
+struct InputIdRecord {
+    bus: String,
+    bus_id: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[derive(Serialize)]
+struct RepeatRecord {
+    delay_ms: u32,
+    period_ms: u32,
+}
+
+/// The fields picked by `DisplayOpts`; `None` stands for a field that was not asked for
+#[derive(Serialize)]
+struct DeviceRecord<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<DeviceEvent>,
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    physical_path: Option<Option<&'a str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<Option<&'a str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<InputIdRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uniq: Option<Option<&'a str>>,
+    /// Null if the device doesn't autorepeat
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repeat: Option<Option<RepeatRecord>>,
+    /// Null if it can't be found out, i.e. without the permission to open the device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grabbed: Option<Option<bool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dechat_virtual: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keys: Option<Vec<KeyRecord>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    properties: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    leds: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    switches: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aliases: Option<Vec<String>>,
+}
+
+fn debug_names<T: std::fmt::Debug>(items: impl IntoIterator<Item = T>) -> Vec<String> {
+    items.into_iter().map(|item| format!("{:?}", item)).collect()
+}
+
+impl<'a> DeviceRecord<'a> {
+    fn new(index: usize, wrapper: &'a DeviceWrapper, display_opts: &DisplayOpts) -> Self {
+        let device = &wrapper.device;
+        Self {
+            event: None,
+            index,
+            path: display_opts.path.then(|| wrapper.path.display().to_string()),
+            physical_path: display_opts.physical_path.then(|| device.physical_path()),
+            name: display_opts.name.then(|| device.name()),
+            id: display_opts.id.then(|| {
+                let input_id = device.input_id();
+                InputIdRecord {
+                    bus: input_id.bus_type().to_string(),
+                    bus_id: input_id.bus_type().0,
+                    vendor: input_id.vendor(),
+                    product: input_id.product(),
+                    version: input_id.version(),
+                }
+            }),
+            uniq: display_opts
+                .uniq
+                .then(|| device.unique_name().filter(|uniq| !uniq.is_empty())),
+            repeat: display_opts.repeat.then(|| {
+                device.get_auto_repeat().map(|repeat| RepeatRecord {
+                    delay_ms: repeat.delay,
+                    period_ms: repeat.period,
+                })
+            }),
+            grabbed: display_opts.status.then(|| wrapper.is_grabbed_elsewhere()),
+            dechat_virtual: display_opts.status.then(|| wrapper.is_dechat_virtual()),
+            keys: display_opts.keys.then(|| {
+                device
+                    .supported_keys()
+                    .into_iter()
+                    .flat_map(|attribute_set| attribute_set.iter())
+                    .map(|key_code| KeyRecord {
+                        name: format!("{:?}", key_code),
+                        code: key_code.code(),
+                    })
+                    .collect()
+            }),
+            properties: display_opts
+                .capabilities
+                .then(|| debug_names(device.properties().iter())),
+            event_types: display_opts
+                .capabilities
+                .then(|| debug_names(device.supported_events().iter())),
+            leds: display_opts
+                .capabilities
+                .then(|| debug_names(device.supported_leds().into_iter().flat_map(|set| set.iter()))),
+            switches: display_opts
+                .capabilities
+                .then(|| debug_names(device.supported_switches().into_iter().flat_map(|set| set.iter()))),
+            aliases: display_opts.aliases.then(|| {
+                wrapper
+                    .aliases()
+                    .iter()
+                    .map(|alias| alias.display().to_string())
+                    .collect()
+            }),
+        }
+    }
 
-            while let Some(key_code) = parts.next() {
-                write!(
-                    f,
-                    "{}={}",
-                    format!("{:?}", key_code).bold().blue(),
-                    key_code.code().to_string().cyan()
-                )?;
-                if parts.peek().is_some() {
-                    write!(f, ", ")?;
+    /// The CSV and table cells, in the order of `DisplayOpts::columns`
+    fn cells(&self) -> Vec<String> {
+        let mut cells = vec![];
+        if let Some(event) = self.event {
+            cells.push(format!("{:?}", event).to_lowercase());
+        }
+        cells.push(self.index.to_string());
+        if let Some(path) = &self.path {
+            cells.push(path.clone());
+        }
+        if let Some(physical_path) = self.physical_path {
+            cells.push(physical_path.unwrap_or_default().to_string());
+        }
+        if let Some(name) = self.name {
+            cells.push(name.unwrap_or_default().to_string());
+        }
+        if let Some(id) = &self.id {
+            cells.push(id.bus.clone());
+            cells.push(format!("{:#x}", id.bus_id));
+            cells.push(format!("{:#x}", id.vendor));
+            cells.push(format!("{:#x}", id.product));
+            cells.push(format!("{:#x}", id.version));
+        }
+        if let Some(uniq) = self.uniq {
+            cells.push(uniq.unwrap_or_default().to_string());
+        }
+        if let Some(repeat) = &self.repeat {
+            match repeat {
+                Some(repeat) => {
+                    cells.push(repeat.delay_ms.to_string());
+                    cells.push(repeat.period_ms.to_string());
                 }
+                None => cells.extend([String::new(), String::new()]),
             }
+        }
+        if let Some(grabbed) = self.grabbed {
+            cells.push(grabbed.map(|grabbed| grabbed.to_string()).unwrap_or_default());
+        }
+        if let Some(dechat_virtual) = self.dechat_virtual {
+            cells.push(dechat_virtual.to_string());
+        }
+        if let Some(keys) = &self.keys {
+            let keys = keys
+                .iter()
+                .map(|key| format!("{}={}", key.name, key.code))
+                .collect::<Vec<_>>();
+            cells.push(keys.join(" "));
+        }
+        for names in [
+            &self.properties,
+            &self.event_types,
+            &self.leds,
+            &self.switches,
+            &self.aliases,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            cells.push(names.join(" "));
+        }
+        cells
+    }
+}
+
+/// Prints the devices in the format picked by `DisplayOpts`, along with their index in the slice
+pub(super) fn print_devices(
+    wrappers: &[DeviceWrapper],
+    display_opts: &DisplayOpts,
+    f: &mut impl std::io::Write,
+) -> anyhow::Result<()> {
+    let records = || {
+        wrappers
+            .iter()
+            .enumerate()
+            .map(|(index, wrapper)| DeviceRecord::new(index, wrapper, display_opts))
+    };
 
-            if has_keys {
-                writeln!(f)?;
+    match display_opts.format {
+        OutputFormat::Text => {
+            for (index, wrapper) in wrappers.iter().enumerate() {
+                DevicePrinter::new(index, wrapper, display_opts).print(f)?;
             }
-        } else {
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *f, &records().collect::<Vec<_>>())?;
             writeln!(f)?;
         }
-        Ok(())
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(f);
+            writer.write_record(display_opts.columns())?;
+            for record in records() {
+                writer.write_record(record.cells())?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Table => {
+            let rows = records().map(|record| record.cells()).collect::<Vec<_>>();
+            write_table(&display_opts.columns(), &rows, f)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the CSV header of `list --watch`; a table can't be streamed, as its column widths depend on all rows
+pub(super) fn print_watch_header(display_opts: &DisplayOpts, f: &mut impl std::io::Write) -> anyhow::Result<()> {
+    match display_opts.format {
+        OutputFormat::Text | OutputFormat::Json => {}
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(f);
+            writer.write_record(display_opts.watch_columns())?;
+            writer.flush()?;
+        }
+        OutputFormat::Table => anyhow::bail!("--watch can't print a table; pick the text, json or csv format"),
+    }
+    Ok(())
+}
+
+/// Prints a device that came or went as soon as it happens: JSON as a record per line, CSV as a row
+/// under `print_watch_header`
+pub(super) fn print_device_event(
+    event: DeviceEvent,
+    index: usize,
+    wrapper: &DeviceWrapper,
+    display_opts: &DisplayOpts,
+    f: &mut impl std::io::Write,
+) -> anyhow::Result<()> {
+    let record = || DeviceRecord {
+        event: Some(event),
+        ..DeviceRecord::new(index, wrapper, display_opts)
+    };
+
+    match display_opts.format {
+        OutputFormat::Text => DevicePrinter::new(index, wrapper, display_opts)
+            .with_event(event)
+            .print(f)?,
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut *f, &record())?;
+            writeln!(f)?;
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut *f);
+            writer.write_record(record().cells())?;
+            writer.flush()?;
+        }
+        OutputFormat::Table => anyhow::bail!("--watch can't print a table"),
+    }
+    f.flush()?;
+    Ok(())
+}
+
+fn write_table(columns: &[&str], rows: &[Vec<String>], f: &mut impl std::io::Write) -> std::io::Result<()> {
+    let widths = columns
+        .iter()
+        .enumerate()
+        .map(|(index, column)| {
+            rows.iter()
+                .map(|row| row[index].chars().count())
+                .chain([column.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+
+    let header = columns.iter().map(|column| column.to_uppercase()).collect::<Vec<_>>();
+    for row in [header].iter().chain(rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>();
+        writeln!(f, "{}", line.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every flag of `DisplayOpts` set or not, as the bits of `flags`
+    fn display_opts(flags: u16) -> DisplayOpts {
+        let flag = |bit: u16| flags & (1 << bit) != 0;
+        DisplayOpts {
+            path: flag(0),
+            physical_path: flag(1),
+            name: flag(2),
+            id: flag(3),
+            keys: flag(4),
+            capabilities: flag(5),
+            repeat: flag(6),
+            uniq: flag(7),
+            aliases: flag(8),
+            status: flag(9),
+            format: OutputFormat::Csv,
+        }
+    }
+
+    /// A record with dummy values for the fields picked by `display_opts`, as `DeviceRecord::new` would fill them;
+    /// `unknown` leaves out the values a device may lack
+    fn dummy_record(display_opts: &DisplayOpts, unknown: bool) -> DeviceRecord<'static> {
+        let value = |value: &'static str| (!unknown).then_some(value);
+        let names = || vec!["A".to_string(), "B".to_string()];
+        DeviceRecord {
+            event: None,
+            index: 0,
+            path: display_opts.path.then(|| "/dev/input/event3".to_string()),
+            physical_path: display_opts.physical_path.then(|| value("usb-0000:04:00.3-3/input0")),
+            name: display_opts.name.then(|| value("Asus Keyboard")),
+            id: display_opts.id.then(|| InputIdRecord {
+                bus: "USB".to_string(),
+                bus_id: 3,
+                vendor: 0xb05,
+                product: 0x19b6,
+                version: 0x110,
+            }),
+            uniq: display_opts.uniq.then(|| value("ABC123")),
+            repeat: display_opts.repeat.then(|| {
+                (!unknown).then_some(RepeatRecord {
+                    delay_ms: 250,
+                    period_ms: 33,
+                })
+            }),
+            grabbed: display_opts.status.then_some((!unknown).then_some(false)),
+            dechat_virtual: display_opts.status.then_some(false),
+            keys: display_opts.keys.then(|| {
+                vec![KeyRecord {
+                    name: "KEY_A".to_string(),
+                    code: 30,
+                }]
+            }),
+            properties: display_opts.capabilities.then(names),
+            event_types: display_opts.capabilities.then(names),
+            leds: display_opts.capabilities.then(names),
+            switches: display_opts.capabilities.then(names),
+            aliases: display_opts.aliases.then(names),
+        }
+    }
+
+    #[test]
+    fn test_cells_match_columns() {
+        for flags in 0..1 << 10 {
+            let display_opts = display_opts(flags);
+            for unknown in [false, true] {
+                let record = dummy_record(&display_opts, unknown);
+                assert_eq!(record.cells().len(), display_opts.columns().len(), "flags {flags:#b}");
+
+                let record = DeviceRecord {
+                    event: Some(DeviceEvent::Added),
+                    ..record
+                };
+                let cells = record.cells();
+                assert_eq!(cells.len(), display_opts.watch_columns().len(), "flags {flags:#b}");
+                assert_eq!(cells[0], "added");
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_table() {
+        let rows = vec![
+            vec!["/dev/input/event3".to_string(), "Asus Keyboard".to_string()],
+            vec!["/dev/input/event10".to_string(), String::new()],
+        ];
+        let mut out = vec![];
+        write_table(&["path", "name"], &rows, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "PATH                NAME\n\
+             /dev/input/event3   Asus Keyboard\n\
+             /dev/input/event10\n"
+        );
     }
 }
@@ -0,0 +1,125 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use evdev::raw_stream::RawDevice;
+use nix::errno::Errno;
+
+/// Failures the user can act upon. Each has a hint and a stable exit code, following `sysexits.h`,
+/// so that systemd units and scripts can branch on them; any other error exits with 1.
+#[derive(Debug, thiserror::Error)]
+pub(super) enum DechatError {
+    #[error("No permission to access /dev/uinput: {0}")]
+    UinputPermission(#[source] std::io::Error),
+
+    #[error("No permission to open {}", .0.display())]
+    DevicePermission(PathBuf),
+
+    #[error("The device {0} is busy: another process has grabbed it")]
+    DeviceBusy(String),
+
+    #[error("No device found for given filters{0}")]
+    NoDeviceMatched(String),
+
+    #[error("The device {} vanished", .0.display())]
+    DeviceVanished(PathBuf),
+
+    #[error("Invalid config: {0:#}")]
+    InvalidConfig(anyhow::Error),
+}
+
+impl DechatError {
+    pub(super) fn hint(&self) -> &'static str {
+        match self {
+            DechatError::UinputPermission(_) => {
+                "Run as root, or grant the user access to /dev/uinput with a udev rule and load the uinput module"
+            }
+            DechatError::DevicePermission(_) => "Run as root, or add the user to the input group",
+            DechatError::DeviceBusy(_) => {
+                "Stop the other program grabbing the device (another dechat-rs instance, evsieve, keyd, ...)"
+            }
+            DechatError::NoDeviceMatched(_) => {
+                "Check the filters against `dechat-rs list -a`, spelled --filter-name, --filter-path and so on there, \
+                 or pass --wait if the device appears later"
+            }
+            DechatError::DeviceVanished(_) => "Reconnect the device, or pass --wait to wait for it",
+            DechatError::InvalidConfig(_) => "Fix the config, then check it with `dechat-rs check-config`",
+        }
+    }
+
+    pub(super) fn exit_code(&self) -> u8 {
+        match self {
+            // EX_NOPERM
+            DechatError::UinputPermission(_) | DechatError::DevicePermission(_) => 77,
+            // EX_TEMPFAIL
+            DechatError::DeviceBusy(_) => 75,
+            // EX_UNAVAILABLE
+            DechatError::NoDeviceMatched(_) => 69,
+            // EX_IOERR
+            DechatError::DeviceVanished(_) => 74,
+            // EX_CONFIG
+            DechatError::InvalidConfig(_) => 78,
+        }
+    }
+}
+
+/// Logs the error along with the hint, and picks the exit code
+pub(super) fn report(err: &anyhow::Error) -> ExitCode {
+    log::error!("{err:#}");
+    match err.chain().find_map(|cause| cause.downcast_ref::<DechatError>()) {
+        Some(dechat_error) => {
+            log::error!("Hint: {}", dechat_error.hint());
+            ExitCode::from(dechat_error.exit_code())
+        }
+        None => ExitCode::FAILURE,
+    }
+}
+
+/// Opens an input device, telling a missing permission and a vanished device apart from other failures
+pub(super) fn open_device(path: &Path) -> anyhow::Result<RawDevice> {
+    RawDevice::open(path).map_err(|err| match err.kind() {
+        ErrorKind::PermissionDenied => DechatError::DevicePermission(path.to_path_buf()).into(),
+        ErrorKind::NotFound => DechatError::DeviceVanished(path.to_path_buf()).into(),
+        _ if err.raw_os_error() == Some(Errno::ENODEV as i32) => DechatError::DeviceVanished(path.to_path_buf()).into(),
+        _ => anyhow::Error::new(err).context(format!("Failed to open {}", path.display())),
+    })
+}
+
+pub(super) fn grab_error(err: std::io::Error, device: &str) -> anyhow::Error {
+    if err.raw_os_error() == Some(Errno::EBUSY as i32) {
+        DechatError::DeviceBusy(device.to_string()).into()
+    } else {
+        anyhow::Error::new(err).context(format!("Failed to grab {device}"))
+    }
+}
+
+pub(super) fn uinput_error(err: std::io::Error) -> anyhow::Error {
+    if err.kind() == ErrorKind::PermissionDenied {
+        DechatError::UinputPermission(err).into()
+    } else {
+        anyhow::Error::new(err).context("Failed to create the virtual device")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_report_exit_code() {
+        let err = Err::<(), _>(DechatError::NoDeviceMatched(String::new()))
+            .context("Failed to start")
+            .unwrap_err();
+        assert_eq!(report(&err), ExitCode::from(69));
+
+        let err = anyhow::anyhow!("Something else");
+        assert_eq!(report(&err), ExitCode::FAILURE);
+
+        let busy = grab_error(std::io::Error::from_raw_os_error(Errno::EBUSY as i32), "event3");
+        assert_eq!(report(&busy), ExitCode::from(75));
+        let gone = grab_error(std::io::Error::from_raw_os_error(Errno::ENODEV as i32), "event3");
+        assert_eq!(report(&gone), ExitCode::FAILURE);
+        assert!(gone.downcast_ref::<std::io::Error>().is_some());
+    }
+}
@@ -0,0 +1,343 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use evdev::raw_stream::RawDevice;
+use evdev::uinput::VirtualDevice;
+use evdev::Device;
+use log::{debug, error, info, warn};
+use nix::errno::Errno;
+use nix::sys::epoll::{epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+
+use crate::cmd::DeviceFilters;
+use crate::config::{Config, ConfigWatcher, LoadedConfig};
+use crate::device_wrapper::DeviceWrapper;
+use crate::error::open_device;
+use crate::execute::get_filtered_devices;
+use crate::hotplug::HotplugWatcher;
+use crate::key_filter::KeyFilter;
+use crate::shutdown::release_on_panic;
+
+const HOTPLUG_TOKEN: u64 = u64::MAX;
+const SIGNAL_TOKEN: u64 = u64::MAX - 1;
+const CONFIG_TOKEN: u64 = u64::MAX - 2;
+
+struct Source {
+    filter: KeyFilter,
+    output: usize,
+    path: PathBuf,
+    device_filters: Vec<DeviceFilters>,
+    connected: bool,
+}
+
+/// Multiplexes several independent key filters in a single thread.
+/// Every filter feeds one of the virtual devices, which may be shared by several filters.
+/// Virtual devices outlive their sources, so a vanished device is re-grabbed as soon as it reconnects.
+pub(super) struct EventLoop {
+    epoll: OwnedFd,
+    hotplug: HotplugWatcher,
+    signals: SignalFd,
+    config: Config,
+    loaded_config: LoadedConfig,
+    config_watcher: Option<ConfigWatcher>,
+    outputs: Vec<VirtualDevice>,
+    sources: Vec<Source>,
+}
+
+impl EventLoop {
+    /// The config is re-read on SIGHUP, and on every change of the config files if `watch_config` is set
+    pub(super) fn new(config: Config, loaded_config: LoadedConfig, watch_config: bool) -> anyhow::Result<Self> {
+        // SAFETY: epoll_create1 returns a new descriptor that nothing else owns
+        let epoll = unsafe { OwnedFd::from_raw_fd(epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)?) };
+
+        let hotplug = HotplugWatcher::new()?;
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, HOTPLUG_TOKEN);
+        epoll_ctl(epoll.as_raw_fd(), EpollOp::EpollCtlAdd, hotplug.as_raw_fd(), &mut event)?;
+
+        // Termination signals are delivered through the loop, so that the devices can be cleaned up
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGINT);
+        mask.add(Signal::SIGTERM);
+        mask.add(Signal::SIGHUP);
+        mask.thread_block()?;
+        let signals = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, SIGNAL_TOKEN);
+        epoll_ctl(epoll.as_raw_fd(), EpollOp::EpollCtlAdd, signals.as_raw_fd(), &mut event)?;
+
+        let config_watcher = if watch_config {
+            let config_watcher = ConfigWatcher::new(&config)?;
+            let mut event = EpollEvent::new(EpollFlags::EPOLLIN, CONFIG_TOKEN);
+            epoll_ctl(
+                epoll.as_raw_fd(),
+                EpollOp::EpollCtlAdd,
+                config_watcher.as_raw_fd(),
+                &mut event,
+            )?;
+            Some(config_watcher)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            epoll,
+            hotplug,
+            signals,
+            config,
+            loaded_config,
+            config_watcher,
+            outputs: vec![],
+            sources: vec![],
+        })
+    }
+
+    /// Registers a virtual device fed by the given devices; vanished devices are looked up again with any of the filters
+    pub(super) fn add_output(
+        &mut self,
+        output: VirtualDevice,
+        device_wrappers: Vec<DeviceWrapper>,
+        device_filters: &[DeviceFilters],
+    ) -> anyhow::Result<()> {
+        release_on_panic(&output)?;
+        let output_index = self.outputs.len();
+        self.outputs.push(output);
+
+        for device_wrapper in device_wrappers {
+            let path = device_wrapper.path.clone();
+            let settings = self.loaded_config.settings_for(&device_wrapper);
+            let filter = device_wrapper.build_key_filter(&settings)?;
+            let mut event = EpollEvent::new(EpollFlags::EPOLLIN, self.sources.len() as u64);
+            epoll_ctl(
+                self.epoll.as_raw_fd(),
+                EpollOp::EpollCtlAdd,
+                filter.as_raw_fd(),
+                &mut event,
+            )?;
+            self.sources.push(Source {
+                filter,
+                output: output_index,
+                path,
+                device_filters: device_filters.to_vec(),
+                connected: true,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Runs until a termination signal arrives
+    pub(super) fn run(&mut self) -> anyhow::Result<()> {
+        for Source { filter, output, .. } in self.sources.iter_mut() {
+            filter.start(&mut self.outputs[*output])?;
+        }
+        info!(
+            "De-chattering {} device(s) into {} virtual device(s)",
+            self.sources.len(),
+            self.outputs.len()
+        );
+
+        let mut events = vec![EpollEvent::empty(); self.sources.len() + 3];
+        loop {
+            let ready = match epoll_wait(self.epoll.as_raw_fd(), &mut events, self.timeout_ms()) {
+                Ok(ready) => ready,
+                Err(Errno::EINTR) => 0,
+                Err(err) => return Err(err.into()),
+            };
+
+            for event in &events[..ready] {
+                if event.data() == HOTPLUG_TOKEN {
+                    self.handle_hotplug()?;
+                    continue;
+                }
+                if event.data() == SIGNAL_TOKEN {
+                    let Some(siginfo) = self.signals.read_signal()? else {
+                        continue;
+                    };
+                    let signal = Signal::try_from(siginfo.ssi_signo as i32)?;
+                    if signal == Signal::SIGHUP {
+                        info!("Received {signal}, reloading the configuration");
+                        self.reload()?;
+                        continue;
+                    }
+                    info!("Received {signal}, shutting down");
+                    self.shutdown();
+                    return Ok(());
+                }
+                if event.data() == CONFIG_TOKEN {
+                    if self.config_watcher.as_ref().map(ConfigWatcher::changed).transpose()? == Some(true) {
+                        info!("The config file changed, reloading the configuration");
+                        self.reload()?;
+                    }
+                    continue;
+                }
+
+                let index = event.data() as usize;
+                let Source { filter, output, .. } = &mut self.sources[index];
+                match filter.process_event_batch(&mut self.outputs[*output]) {
+                    Ok(()) => {}
+                    Err(err) if is_device_gone(&err) => self.disconnect(index)?,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            for source in self.sources.iter_mut().filter(|source| source.connected) {
+                source.filter.handle_timers(&mut self.outputs[source.output])?;
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        for source in self.sources.iter_mut().filter(|source| source.connected) {
+            if let Err(err) = source.filter.stop(&mut self.outputs[source.output]) {
+                warn!("Failed to stop de-chattering {}: {err}", source.path.display());
+            }
+        }
+    }
+
+    /// An invalid config is rejected as a whole, and the previous settings keep running
+    fn reload(&mut self) -> anyhow::Result<()> {
+        self.loaded_config = match self.config.load() {
+            Ok(loaded_config) => loaded_config,
+            Err(err) => {
+                error!("Keeping the previous configuration: {err:#}");
+                return Ok(());
+            }
+        };
+
+        // Disconnected sources pick the new settings up when they reconnect
+        for source in self.sources.iter_mut().filter(|source| source.connected) {
+            let device = match Device::open(&source.path) {
+                Ok(device) => device,
+                Err(err) => {
+                    warn!("Can't reconfigure {}: {err}", source.path.display());
+                    continue;
+                }
+            };
+            let device_wrapper = DeviceWrapper::from((source.path.clone(), device));
+            let settings = self.loaded_config.settings_for(&device_wrapper);
+            info!("Applying the configuration to {}", device_wrapper.name());
+            // One failing device must not stop the others from being reconfigured
+            if let Err(err) = source.filter.reconfigure(&settings, &mut self.outputs[source.output]) {
+                error!("Failed to reconfigure {}: {err:#}", source.path.display());
+            }
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self, index: usize) -> anyhow::Result<()> {
+        let source = &mut self.sources[index];
+        warn!(
+            "The original keyboard {} [{}] vanished; waiting for it to reconnect",
+            source.filter.device().name().unwrap_or_default(),
+            source.path.display()
+        );
+
+        epoll_ctl(
+            self.epoll.as_raw_fd(),
+            EpollOp::EpollCtlDel,
+            source.filter.as_raw_fd(),
+            None,
+        )?;
+        source.connected = false;
+        source.filter.disconnect(&mut self.outputs[source.output])
+    }
+
+    fn handle_hotplug(&mut self) -> anyhow::Result<()> {
+        let added = self.hotplug.read_added()?;
+        if added.is_empty() || self.sources.iter().all(|source| source.connected) {
+            return Ok(());
+        }
+        debug!("Input nodes added: {:?}", added);
+
+        for index in 0..self.sources.len() {
+            if self.sources[index].connected {
+                continue;
+            }
+            if let Err(err) = self.reconnect(index) {
+                warn!("Failed to reconnect the original keyboard: {err}");
+            }
+        }
+        Ok(())
+    }
+
+    fn reconnect(&mut self, index: usize) -> anyhow::Result<()> {
+        let in_use = self
+            .sources
+            .iter()
+            .filter(|source| source.connected)
+            .map(|source| source.path.clone())
+            .collect::<Vec<_>>();
+
+        let source = &mut self.sources[index];
+        let mut candidates = get_filtered_devices(&source.device_filters)
+            .into_iter()
+            .filter(|device_wrapper| !in_use.contains(&device_wrapper.path))
+            .collect::<Vec<_>>();
+
+        // Several nodes of the same keyboard share the name and ids, and only differ by the physical path
+        let position = candidates
+            .iter()
+            .position(|device_wrapper| is_same_device(source.filter.device(), &device_wrapper.device, true))
+            .or_else(|| {
+                candidates
+                    .iter()
+                    .position(|device_wrapper| is_same_device(source.filter.device(), &device_wrapper.device, false))
+            });
+        let Some(position) = position else {
+            return Ok(());
+        };
+
+        let device_wrapper = candidates.swap_remove(position);
+        info!(
+            "The original keyboard {} reconnected as {}",
+            device_wrapper.name(),
+            device_wrapper.path.display()
+        );
+
+        let orig_keyboard = open_device(&device_wrapper.path)?;
+        let settings = self.loaded_config.settings_for(&device_wrapper);
+        source
+            .filter
+            .reconnect(orig_keyboard, &settings, &mut self.outputs[source.output])?;
+        source.path = device_wrapper.path;
+        source.connected = true;
+
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, index as u64);
+        epoll_ctl(
+            self.epoll.as_raw_fd(),
+            EpollOp::EpollCtlAdd,
+            source.filter.as_raw_fd(),
+            &mut event,
+        )?;
+        Ok(())
+    }
+
+    /// Time until the nearest pending switch change is due; -1 blocks until any device has events
+    fn timeout_ms(&self) -> isize {
+        let Some(deadline) = self
+            .sources
+            .iter()
+            .filter(|source| source.connected)
+            .filter_map(|source| source.filter.next_deadline())
+            .min()
+        else {
+            return -1;
+        };
+
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as isize
+    }
+}
+
+fn is_same_device(lost: &RawDevice, candidate: &Device, strict: bool) -> bool {
+    lost.name() == candidate.name()
+        && lost.input_id() == candidate.input_id()
+        && (!strict || lost.physical_path() == candidate.physical_path())
+}
+
+fn is_device_gone(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .and_then(std::io::Error::raw_os_error)
+        .is_some_and(|errno| errno == Errno::ENODEV as i32)
+}
@@ -1,8 +1,18 @@
-use crate::cmd::{Cli, Command, StringFilter};
+use crate::check_config::RuleTable;
+use crate::cmd::{Cli, Command, DeviceFilters};
+use crate::config::{Config, FilterSettings};
 use crate::device_wrapper::DeviceWrapper;
-use crate::display::{DevicePrinter, DisplayOpts};
+use crate::display::{print_device_event, print_devices, print_watch_header, DeviceEvent, DisplayOpts};
+use crate::error::DechatError;
+use crate::event_loop::EventLoop;
+use crate::hotplug::HotplugWatcher;
+use crate::identify::{filters_for, format_filters, wait_for_key_press, Identified};
 use crate::traits::Execute;
-use log::info;
+use evdev::Device;
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
 
 impl Execute for Cli {
     fn execute(self) -> anyhow::Result<()> {
@@ -13,23 +23,70 @@ impl Execute for Cli {
 impl Execute for Command {
     fn execute(self) -> anyhow::Result<()> {
         match self {
-            cmd @ Command::List { .. } => {
+            Command::List { ref filters, watch, .. } => {
                 let mut stdout = std::io::stdout();
-                let display_opts = DisplayOpts::try_from(cmd)?;
+                if !stdout.is_terminal() {
+                    colored::control::set_override(false);
+                }
+                let display_opts = DisplayOpts::try_from(&self)?;
 
-                for device in DeviceWrapper::list_wrapped_divices() {
-                    DevicePrinter::new(&device, &display_opts).print(&mut stdout)?;
+                // The same selection as de-chatter makes, so that the printed index can be passed to --index
+                let filters = [DeviceFilters::from(filters.clone())];
+                if watch {
+                    watch_devices(&filters, &display_opts, &mut stdout)?;
+                } else {
+                    print_devices(&get_filtered_devices(&filters), &display_opts, &mut stdout)?;
                 }
             }
             Command::DeChatter {
                 timeouts,
-                name,
-                path,
-                physical_path,
+                switch_timeouts,
+                wheel_reversal,
+                config,
+                watch_config,
+                filters,
                 index,
+                wait,
+                all,
+                merge,
+                grab_timeout,
                 skip_first,
             } => {
-                let mut device_wrappers = get_filtered_devices(&name, &path, &physical_path);
+                if skip_first {
+                    warn!("--skip-first is deprecated and ignored: the grab waits until all keys are released");
+                }
+
+                let config = Config::new(
+                    FilterSettings {
+                        timeouts,
+                        switch_timeouts,
+                        wheel_reversal,
+                        grab_timeout,
+                    },
+                    config,
+                );
+                let loaded_config = config.load()?;
+
+                let auto_keyboards = filters.auto_keyboards;
+                // Without filters on the command line, every device matching a profile of the config is taken
+                let by_profiles = filters.is_empty() && !loaded_config.profiles().is_empty();
+                let device_filters = if by_profiles {
+                    info!("No filters given; taking every device matching the config profiles");
+                    loaded_config
+                        .profiles()
+                        .iter()
+                        .map(|profile| profile.filters.clone())
+                        .collect::<Vec<_>>()
+                } else {
+                    vec![filters]
+                };
+                let all = all || ((by_profiles || auto_keyboards) && !merge);
+
+                let required = if all || merge { 1 } else { index + 1 };
+                let mut device_wrappers = match wait {
+                    Some(timeout) => wait_for_devices(&device_filters, required, timeout)?,
+                    None => get_filtered_devices(&device_filters),
+                };
                 for (index, device) in device_wrappers.iter().enumerate() {
                     info!(
                         "A device with index={index} after applying filters: {} {} [{}]",
@@ -39,11 +96,139 @@ impl Execute for Command {
                     );
                 }
                 if index >= device_wrappers.len() {
-                    anyhow::bail!("No device found for given filters");
+                    return Err(DechatError::NoDeviceMatched(String::new()).into());
+                }
+                let groups = if merge {
+                    vec![device_wrappers]
+                } else if all {
+                    device_wrappers
+                        .into_iter()
+                        .map(|device_wrapper| vec![device_wrapper])
+                        .collect()
+                } else {
+                    vec![vec![device_wrappers.swap_remove(index)]]
+                };
+
+                let mut fake_keyboards = vec![];
+                for group in groups {
+                    let virtual_name = group.iter().find_map(|device_wrapper| {
+                        loaded_config
+                            .profile_for(device_wrapper)
+                            .and_then(|profile| profile.virtual_name.as_deref())
+                    });
+                    let fake_keyboard = DeviceWrapper::create_fake_keyboard(&group, virtual_name)?;
+                    fake_keyboards.push((fake_keyboard, group));
+                }
+
+                let mut event_loop = EventLoop::new(config, loaded_config, watch_config)?;
+                for (fake_keyboard, group) in fake_keyboards {
+                    event_loop.add_output(fake_keyboard, group, &device_filters)?;
+                }
+                event_loop.run()?;
+            }
+            Command::CheckConfig {
+                timeouts,
+                config,
+                keys,
+                filters,
+                index,
+            } => {
+                let config = Config::new(
+                    FilterSettings {
+                        timeouts,
+                        ..Default::default()
+                    },
+                    config,
+                );
+                let loaded_config = config.load()?;
+
+                let (settings, codes) = match keys {
+                    Some(keys) => {
+                        if !filters.is_empty() {
+                            anyhow::bail!("--keys can't be combined with device filters");
+                        }
+                        (loaded_config.default_settings(), keys.codes().collect::<Vec<_>>())
+                    }
+                    None => {
+                        if filters.is_empty() {
+                            anyhow::bail!("Pass device filters to check the keys of a device, or --keys");
+                        }
+                        let mut device_wrappers = get_filtered_devices(&[filters]);
+                        if index >= device_wrappers.len() {
+                            return Err(DechatError::NoDeviceMatched(String::new()).into());
+                        }
+                        let device_wrapper = device_wrappers.swap_remove(index);
+                        info!(
+                            "Checking the keys of {} [{}]",
+                            device_wrapper.name(),
+                            device_wrapper.path.display()
+                        );
+                        let codes = device_wrapper
+                            .device
+                            .supported_keys()
+                            .iter()
+                            .flat_map(|attribute_set| attribute_set.iter())
+                            .map(|key_code| key_code.code())
+                            .collect::<Vec<_>>();
+                        (loaded_config.settings_for(&device_wrapper), codes)
+                    }
+                };
+
+                let table = RuleTable::new(&settings, &codes);
+                table.print(&settings, &mut std::io::stdout())?;
+                if table.has_problems() {
+                    let err = anyhow::anyhow!("conflicting or out of range rules, see above");
+                    return Err(DechatError::InvalidConfig(err).into());
+                }
+            }
+            Command::Identify {
+                wait,
+                start,
+                timeouts,
+                config,
+            } => {
+                let Identified {
+                    device_wrapper,
+                    key_code,
+                } = wait_for_key_press(wait)?;
+                let (filters, index) = filters_for(&device_wrapper);
+
+                let mut stdout = std::io::stdout();
+                writeln!(
+                    stdout,
+                    "{:?} was pressed on {}",
+                    key_code,
+                    device_wrapper.path.display()
+                )?;
+                writeln!(stdout, "Name: {}", device_wrapper.name())?;
+                writeln!(
+                    stdout,
+                    "Physical path: {}",
+                    device_wrapper.device.physical_path().unwrap_or("?")
+                )?;
+                for alias in device_wrapper.aliases() {
+                    writeln!(stdout, "Alias: {}", alias.display())?;
+                }
+                writeln!(stdout, "Filters: {}", format_filters(&device_wrapper, index))?;
+
+                if start {
+                    drop(device_wrapper);
+                    return Command::DeChatter {
+                        timeouts,
+                        switch_timeouts: vec![],
+                        wheel_reversal: None,
+                        config,
+                        watch_config: false,
+                        filters,
+                        index,
+                        wait: None,
+                        all: false,
+                        merge: false,
+                        grab_timeout: None,
+                        skip_first: false,
+                    }
+                    .execute();
                 }
-                let device_wrapper = device_wrappers.swap_remove(index);
-                let mut filter = device_wrapper.build_key_filter(timeouts, skip_first)?;
-                filter.block()?;
             }
         }
 
@@ -51,25 +236,102 @@ impl Execute for Command {
     }
 }
 
-fn get_filtered_devices(
-    name: &[StringFilter],
-    path: &[StringFilter],
-    physical_path: &[StringFilter],
-) -> Vec<DeviceWrapper> {
+/// Devices matching any of the filter sets
+pub(super) fn get_filtered_devices(filters: &[DeviceFilters]) -> Vec<DeviceWrapper> {
     DeviceWrapper::list_wrapped_divices()
         .into_iter()
-        .filter(|device_wrapper| {
-            path.iter()
-                .all(|filter| filter.matches(device_wrapper.path.display().to_string()))
-        })
-        .filter(|device_wrapper| {
-            name.iter()
-                .all(|filter| filter.matches(device_wrapper.device.name().unwrap_or_default()))
-        })
-        .filter(|device_wrapper| {
-            physical_path
-                .iter()
-                .all(|filter| filter.matches(device_wrapper.device.physical_path().unwrap_or_default()))
-        })
+        .filter(|device_wrapper| filters.iter().any(|filters| filters.matches(device_wrapper)))
         .collect::<Vec<_>>()
 }
+
+/// Prints the matching devices, then every device that starts or stops matching as input nodes come and go
+fn watch_devices(
+    filters: &[DeviceFilters],
+    display_opts: &DisplayOpts,
+    f: &mut impl std::io::Write,
+) -> anyhow::Result<()> {
+    // Subscribe before listing, so that a device plugged in meanwhile is not missed
+    let hotplug = HotplugWatcher::new()?;
+    print_watch_header(display_opts, f)?;
+
+    let mut device_wrappers = get_filtered_devices(filters);
+    for (index, device_wrapper) in device_wrappers.iter().enumerate() {
+        print_device_event(DeviceEvent::Present, index, device_wrapper, display_opts, f)?;
+    }
+
+    loop {
+        if !hotplug.wait(None)? || hotplug.read_changed()?.is_empty() {
+            continue;
+        }
+
+        // Compare whole listings: a node only becomes readable once udev has set its permissions
+        let current = get_filtered_devices(filters);
+        for (index, device_wrapper) in device_wrappers.iter().enumerate() {
+            if !current.contains(device_wrapper) {
+                print_device_event(DeviceEvent::Removed, index, device_wrapper, display_opts, f)?;
+            }
+        }
+        for (index, device_wrapper) in current.iter().enumerate() {
+            if !device_wrappers.contains(device_wrapper) {
+                print_device_event(DeviceEvent::Added, index, device_wrapper, display_opts, f)?;
+            }
+        }
+        device_wrappers = current;
+    }
+}
+
+/// Blocks until at least `required` devices match the filters, logging every new input device that shows up
+fn wait_for_devices(
+    filters: &[DeviceFilters],
+    required: usize,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Vec<DeviceWrapper>> {
+    // Subscribe before listing, so that a device plugged in meanwhile is not missed
+    let hotplug = HotplugWatcher::new()?;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut seen = HashSet::new();
+
+    loop {
+        let device_wrappers = get_filtered_devices(filters);
+        if device_wrappers.len() >= required {
+            return Ok(device_wrappers);
+        }
+
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            let within = format!(" within {:?}", timeout.unwrap_or_default());
+            return Err(DechatError::NoDeviceMatched(within).into());
+        }
+
+        info!(
+            "Waiting for devices matching the filters: {} of {} found",
+            device_wrappers.len(),
+            required
+        );
+        if !hotplug.wait(remaining)? {
+            continue;
+        }
+
+        for path in hotplug.read_added()? {
+            if seen.contains(&path) {
+                continue;
+            }
+            let device = match Device::open(&path) {
+                Ok(device) => device,
+                Err(err) => {
+                    debug!("Can't open {}: {err}", path.display());
+                    continue;
+                }
+            };
+            seen.insert(path.clone());
+            let device_wrapper = DeviceWrapper::from((path, device));
+            info!(
+                "A device showed up: {} {} [{}]; matches filters: {}",
+                device_wrapper.name(),
+                device_wrapper.device.physical_path().unwrap_or_default(),
+                device_wrapper.path.display(),
+                filters.iter().any(|filters| filters.matches(&device_wrapper))
+            );
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+
+const INPUT_DIR: &str = "/dev/input";
+
+/// Watches `/dev/input` for new and removed event nodes
+pub(super) struct HotplugWatcher {
+    inotify: Inotify,
+}
+
+impl HotplugWatcher {
+    pub(super) fn new() -> anyhow::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        // udev adjusts the node attributes after it is created, and the node may only be usable after that
+        inotify.add_watch(
+            INPUT_DIR,
+            AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB | AddWatchFlags::IN_DELETE,
+        )?;
+        Ok(Self { inotify })
+    }
+
+    /// Blocks until a notification arrives or the timeout expires; returns false on timeout
+    pub(super) fn wait(&self, timeout: Option<Duration>) -> anyhow::Result<bool> {
+        let timeout_ms = match timeout {
+            Some(timeout) => timeout.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let mut fds = [PollFd::new(self.as_raw_fd(), PollFlags::POLLIN)];
+
+        match poll(&mut fds, timeout_ms) {
+            Ok(ready) => Ok(ready > 0),
+            Err(Errno::EINTR) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Drains pending notifications and returns the event nodes that were added or changed
+    pub(super) fn read_added(&self) -> anyhow::Result<Vec<PathBuf>> {
+        self.read_events(|mask| !mask.contains(AddWatchFlags::IN_DELETE))
+    }
+
+    /// Drains pending notifications and returns the event nodes that were added, changed or removed
+    pub(super) fn read_changed(&self) -> anyhow::Result<Vec<PathBuf>> {
+        self.read_events(|_| true)
+    }
+
+    fn read_events(&self, accept: impl Fn(AddWatchFlags) -> bool) -> anyhow::Result<Vec<PathBuf>> {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut paths = events
+            .into_iter()
+            .filter(|event| accept(event.mask))
+            .filter_map(|event| event.name)
+            .filter(|name| name.to_string_lossy().starts_with("event"))
+            .map(|name| Path::new(INPUT_DIR).join(name))
+            .collect::<Vec<_>>();
+        paths.sort_unstable();
+        paths.dedup();
+        Ok(paths)
+    }
+}
+
+impl AsRawFd for HotplugWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
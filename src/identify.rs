@@ -0,0 +1,164 @@
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+use evdev::{EventSummary, KeyCode};
+use log::{debug, info};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+
+use crate::cmd::{parse_filter, DeviceFilters, StringFilter};
+use crate::device_wrapper::DeviceWrapper;
+use crate::execute::get_filtered_devices;
+
+/// Keyboard keys come before the buttons of mice and joysticks
+const BTN_MISC: u16 = 0x100;
+
+pub(super) struct Identified {
+    pub(super) device_wrapper: DeviceWrapper,
+    pub(super) key_code: KeyCode,
+}
+
+/// Listens on every device with keys, without grabbing them, until a key is pressed on one of them.
+/// Releases are ignored, so the release of the key that started the command doesn't count
+pub(super) fn wait_for_key_press(timeout: Option<Duration>) -> anyhow::Result<Identified> {
+    let mut device_wrappers = DeviceWrapper::list_wrapped_divices()
+        .into_iter()
+        .filter(|device_wrapper| !device_wrapper.is_dechat_virtual())
+        .filter(|device_wrapper| {
+            device_wrapper
+                .device
+                .supported_keys()
+                .is_some_and(|keys| keys.iter().any(|key_code| key_code.code() < BTN_MISC))
+        })
+        .collect::<Vec<_>>();
+    if device_wrappers.is_empty() {
+        anyhow::bail!("No input device with keys found");
+    }
+    for device_wrapper in &device_wrappers {
+        device_wrapper.device.set_nonblocking(true)?;
+        debug!(
+            "Listening on {} [{}]",
+            device_wrapper.name(),
+            device_wrapper.path.display()
+        );
+    }
+
+    // The prompt is the point of the command, so it must not depend on the log level
+    writeln!(
+        std::io::stderr(),
+        "Press a key on the keyboard to identify (listening on {} devices)",
+        device_wrappers.len()
+    )?;
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            anyhow::bail!("No key was pressed within {:?}", timeout.unwrap_or_default());
+        }
+        let timeout_ms = match remaining {
+            Some(remaining) => remaining.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+
+        let mut fds = device_wrappers
+            .iter()
+            .map(|device_wrapper| PollFd::new(device_wrapper.device.as_raw_fd(), PollFlags::POLLIN))
+            .collect::<Vec<_>>();
+        match poll(&mut fds, timeout_ms) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+        let ready = fds
+            .iter()
+            .map(|fd| fd.revents().is_some_and(|revents| !revents.is_empty()))
+            .collect::<Vec<_>>();
+
+        for index in (0..device_wrappers.len()).rev() {
+            if !ready[index] {
+                continue;
+            }
+            let fetched = device_wrappers[index]
+                .device
+                .fetch_events()
+                .map(|events| events.collect::<Vec<_>>());
+            let events = match fetched {
+                Ok(events) => events,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(err) => {
+                    // A device unplugged meanwhile is no candidate anymore
+                    info!("Stopped listening on {}: {err}", device_wrappers[index].path.display());
+                    device_wrappers.swap_remove(index);
+                    continue;
+                }
+            };
+            let pressed = events.into_iter().find_map(|event| match event.destructure() {
+                EventSummary::Key(_, key_code, 1) if key_code.code() < BTN_MISC => Some(key_code),
+                _ => None,
+            });
+            if let Some(key_code) = pressed {
+                return Ok(Identified {
+                    device_wrapper: device_wrappers.swap_remove(index),
+                    key_code,
+                });
+            }
+        }
+    }
+}
+
+/// Filters on the name and the physical path, plus the index among the devices they match
+pub(super) fn filters_for(device_wrapper: &DeviceWrapper) -> (DeviceFilters, usize) {
+    let mut filters = DeviceFilters {
+        name: vec![StringFilter::Equals(device_wrapper.name().into())],
+        ..Default::default()
+    };
+    if let Some(physical_path) = device_wrapper.device.physical_path().filter(|path| !path.is_empty()) {
+        filters.physical_path.push(StringFilter::Equals(physical_path.into()));
+    }
+
+    let index = get_filtered_devices(&[filters.clone()])
+        .iter()
+        .position(|candidate| candidate.path == device_wrapper.path)
+        .unwrap_or_default();
+    (filters, index)
+}
+
+/// The de-chatter arguments selecting the device, quoted for a shell
+pub(super) fn format_filters(device_wrapper: &DeviceWrapper, index: usize) -> String {
+    let mut args = vec![format!("--name {}", shell_quote(&exact_filter(device_wrapper.name())))];
+    if let Some(physical_path) = device_wrapper.device.physical_path().filter(|path| !path.is_empty()) {
+        args.push(format!("--physical-path {}", shell_quote(&exact_filter(physical_path))));
+    }
+    if index > 0 {
+        args.push(format!("--index {index}"));
+    }
+    args.join(" ")
+}
+
+/// A filter matching exactly the value, which may itself look like a prefixed filter
+fn exact_filter(raw: &str) -> String {
+    match parse_filter(raw) {
+        Ok(StringFilter::Equals(_)) => raw.to_string(),
+        _ => format!("r:^{}$", regex::escape(raw)),
+    }
+}
+
+fn shell_quote(raw: &str) -> String {
+    format!("'{}'", raw.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("Asus Keyboard"), "'Asus Keyboard'");
+        assert_eq!(shell_quote("Bob's Mouse"), r"'Bob'\''s Mouse'");
+
+        assert_eq!(exact_filter("Asus Keyboard"), "Asus Keyboard");
+        assert_eq!(exact_filter("!c:Mouse"), r"r:^!c:Mouse$");
+        assert!(parse_filter(&exact_filter("s:x (y)")).unwrap().matches("s:x (y)"));
+    }
+}
@@ -1,109 +1,397 @@
+use std::collections::{BTreeMap, HashMap};
 use std::hint::unreachable_unchecked;
-use std::time::{Duration, Instant};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, Instant, SystemTime};
 
+use evdev::raw_stream::RawDevice;
 use evdev::uinput::VirtualDevice;
-use evdev::{Device, EvdevEnum, EventSummary, InputEvent, KeyCode};
+use evdev::{
+    AttributeSet, AttributeSetRef, EvdevEnum, EventSummary, InputEvent, KeyCode, KeyEvent, MiscCode, RelativeAxisCode,
+    SwitchCode, SwitchEvent, SynchronizationCode,
+};
 use log::{debug, error, info, trace, warn};
 
-use crate::cmd::KeyRangeTimeout;
+use crate::cmd::{KeyRangeTimeout, ScanRangeTimeout, TimeoutRange};
+use crate::config::{FilterSettings, DEFAULT_GRAB_TIMEOUT};
+use crate::error::grab_error;
+use crate::key_spec::{collect_conflicts, resolve_key_rules, KeyRule};
 use crate::key_state::KeyState;
+use crate::switch_state::SwitchState;
+use crate::wheel_state::WheelState;
+
+const WHEEL_AXES: [RelativeAxisCode; 4] = [
+    RelativeAxisCode::REL_WHEEL,
+    RelativeAxisCode::REL_WHEEL_HI_RES,
+    RelativeAxisCode::REL_HWHEEL,
+    RelativeAxisCode::REL_HWHEEL_HI_RES,
+];
 
 pub(super) struct KeyFilter {
     key_timeouts: Vec<Option<Duration>>,
     tracker: Vec<KeyState>,
-    orig_keyboard: Device,
-    fake_keyboard: VirtualDevice,
+    orig_keyboard: RawDevice,
     stats: Vec<usize>,
+    scan_timeouts: Vec<ScanRangeTimeout>,
+    scan_tracker: HashMap<u32, KeyState>,
+    scan_stats: BTreeMap<u32, usize>,
+    frame_scan_code: Option<u32>,
+    switch_timeouts: Vec<Option<Duration>>,
+    switch_tracker: Vec<SwitchState>,
+    switch_stats: Vec<usize>,
+    wheel_timeout: Option<Duration>,
+    wheel_tracker: Vec<WheelState>,
+    wheel_stats: Vec<usize>,
+    held_keys: AttributeSet<KeyCode>,
     last_stats_printed: Instant,
-    skip_first: bool,
+    grab_timeout: Duration,
+    grab_deadline: Option<Instant>,
+    grabbed: bool,
+    dropped: bool,
+    drop_recoveries: usize,
 }
 
 impl KeyFilter {
-    pub(super) fn new(
-        timeouts: Vec<KeyRangeTimeout>,
-        orig_keyboard: Device,
-        fake_keyboard: VirtualDevice,
-        skip_first: bool,
-    ) -> Self {
-        let max_keyboard_code = orig_keyboard
-            .supported_keys()
-            .iter()
-            .flat_map(|attribute_set| attribute_set.iter())
-            .map(|key_code| key_code.to_index())
-            .max()
-            .unwrap_or_default();
+    pub(super) fn new(settings: &FilterSettings, orig_keyboard: RawDevice) -> Self {
+        let (key_timeouts, scan_timeouts, switch_timeouts) = build_lookup_tables(settings, &orig_keyboard);
 
-        let max_requested_key_code = timeouts
-            .iter()
-            .map(|key_range_timeout| (*key_range_timeout.range.end()) as usize)
-            .max()
-            .unwrap_or_default();
-
-        let required_size = max_keyboard_code.min(max_requested_key_code) + 1;
-        let mut key_timeouts = vec![None; required_size];
-
-        for key_range_timeout in timeouts {
-            for key_code in key_range_timeout.range.clone().map(usize::from) {
-                if key_code >= required_size {
-                    warn!(
-                        "Key code {} from provided range {:?} is out of keyboard's range: keyboard has {} key codes",
-                        key_code, key_range_timeout, max_keyboard_code
-                    );
-                    break;
-                }
+        let switch_tracker = read_switch_tracker(&orig_keyboard, switch_timeouts.len());
 
-                if let Some(timeout) = key_timeouts[key_code] {
-                    warn!(
-                        "Key code {:?} is already throttled with timeout {:?}, ignoring the new timeout {:?}",
-                        key_code, timeout, key_range_timeout.timeout
-                    );
-                    continue;
-                }
-
-                key_timeouts[key_code] = Some(key_range_timeout.timeout);
-            }
-        }
+        let wheel_size = WHEEL_AXES.iter().map(|axis| axis.0 as usize).max().unwrap_or_default() + 1;
 
         Self {
-            tracker: vec![KeyState::default(); required_size],
-            stats: vec![0; required_size],
+            tracker: vec![KeyState::default(); key_timeouts.len()],
+            stats: vec![0; key_timeouts.len()],
+            scan_timeouts,
+            scan_tracker: HashMap::new(),
+            scan_stats: BTreeMap::new(),
+            frame_scan_code: None,
+            switch_stats: vec![0; switch_timeouts.len()],
+            switch_timeouts,
+            switch_tracker,
+            wheel_timeout: settings.wheel_reversal,
+            wheel_tracker: vec![WheelState::default(); wheel_size],
+            wheel_stats: vec![0; wheel_size],
             key_timeouts,
             orig_keyboard,
-            fake_keyboard,
+            held_keys: AttributeSet::new(),
             last_stats_printed: Instant::now(),
-            skip_first,
+            grab_timeout: settings.grab_timeout.unwrap_or(DEFAULT_GRAB_TIMEOUT),
+            grab_deadline: None,
+            grabbed: false,
+            dropped: false,
+            drop_recoveries: 0,
         }
     }
 
-    pub(super) fn block(&mut self) -> anyhow::Result<()> {
-        if self.skip_first {
-            for event in self.orig_keyboard.fetch_events()? {
-                info!("Skipping {:?}", event);
-            }
+    /// Grabs the original keyboard once no keys are physically held on it.
+    /// Otherwise, the system would never see the release of a key pressed before the grab (i.e. the Enter that
+    /// started the program), so the grab is postponed until the keys are released, with a warning every grab timeout.
+    pub(super) fn start(&mut self, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        let held = self.orig_keyboard.get_key_state()?;
+        if held.iter().next().is_none() {
+            return self.grab(output);
         }
 
-        self.orig_keyboard.grab()?;
+        info!(
+            "Waiting for the keys to be released before grabbing {}: {:?}",
+            self.label(),
+            held.iter().collect::<Vec<_>>()
+        );
+        self.grab_deadline = Some(Instant::now() + self.grab_timeout);
+        Ok(())
+    }
+
+    fn grab(&mut self, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        self.orig_keyboard.grab().map_err(|err| grab_error(err, self.label()))?;
+        self.grabbed = true;
+        self.grab_deadline = None;
         info!(
             "Grabbed the original keyboard: {}",
             self.orig_keyboard.physical_path().unwrap_or_default()
         );
 
-        loop {
-            self.process_event_batch()?;
+        self.sync_switches(output)
+    }
+
+    /// Uinput devices start with all switches off, and a switch may have changed while the device was
+    /// disconnected, so the stable physical state must be replayed; repeated values are dropped by the kernel
+    fn sync_switches(&mut self, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        for (index, state) in self.switch_tracker.iter().enumerate() {
+            if self.switch_timeouts[index].is_none() {
+                continue;
+            }
+            let switch_code = SwitchCode::from_index(index);
+            debug!("Restoring the switch state {switch_code:?}={}", state.stable());
+            output.emit(&[*SwitchEvent::new(switch_code, state.stable())])?;
+        }
+        Ok(())
+    }
+
+    /// Applies new settings without releasing the device. Keys, switches and the wheel keep their tracked state
+    /// unless their timeout changed; a changed switch is re-read from the device.
+    pub(super) fn reconfigure(&mut self, settings: &FilterSettings, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        let (key_timeouts, scan_timeouts, switch_timeouts) = build_lookup_tables(settings, &self.orig_keyboard);
+
+        let now = SystemTime::now();
+        let mut tracker = Vec::with_capacity(key_timeouts.len());
+        for (index, timeout) in key_timeouts.iter().enumerate() {
+            let state = match self.tracker.get(index) {
+                Some(state) if self.key_timeouts[index] == *timeout => state.clone(),
+                _ if self.held_keys.contains(KeyCode::from_index(index)) => KeyState::Down(now),
+                _ => KeyState::default(),
+            };
+            tracker.push(state);
+        }
+        self.tracker = tracker;
+        self.stats.resize(key_timeouts.len(), 0);
+        self.key_timeouts = key_timeouts;
+
+        self.scan_tracker.retain(|scan_code, _| {
+            scan_timeout(&self.scan_timeouts, *scan_code) == scan_timeout(&scan_timeouts, *scan_code)
+        });
+        self.scan_timeouts = scan_timeouts;
+
+        let physical = read_switch_tracker(&self.orig_keyboard, switch_timeouts.len());
+        let mut switch_tracker = Vec::with_capacity(switch_timeouts.len());
+        for (index, state) in physical.into_iter().enumerate() {
+            match self.switch_tracker.get(index) {
+                Some(previous) if self.switch_timeouts[index] == switch_timeouts[index] => {
+                    switch_tracker.push(previous.clone());
+                }
+                _ => {
+                    if self.grabbed {
+                        let switch_code = SwitchCode::from_index(index);
+                        debug!("Restoring the switch state {switch_code:?}={}", state.stable());
+                        output.emit(&[*SwitchEvent::new(switch_code, state.stable())])?;
+                    }
+                    switch_tracker.push(state);
+                }
+            }
+        }
+        self.switch_tracker = switch_tracker;
+        self.switch_stats.resize(switch_timeouts.len(), 0);
+        self.switch_timeouts = switch_timeouts;
+
+        if self.wheel_timeout != settings.wheel_reversal {
+            self.wheel_tracker.fill(WheelState::default());
+            self.wheel_timeout = settings.wheel_reversal;
+        }
+        self.grab_timeout = settings.grab_timeout.unwrap_or(DEFAULT_GRAB_TIMEOUT);
+        Ok(())
+    }
+
+    pub(super) fn device(&self) -> &RawDevice {
+        &self.orig_keyboard
+    }
+
+    /// Replaces a vanished original keyboard with its reconnected node, starting over with a clean state.
+    /// The settings are resolved again, since the node may match another profile now
+    pub(super) fn reconnect(
+        &mut self,
+        orig_keyboard: RawDevice,
+        settings: &FilterSettings,
+        output: &mut VirtualDevice,
+    ) -> anyhow::Result<()> {
+        self.orig_keyboard = orig_keyboard;
+        self.grabbed = false;
+        self.dropped = false;
+        self.tracker.fill(KeyState::default());
+        self.scan_tracker.clear();
+        self.frame_scan_code = None;
+        self.switch_tracker = read_switch_tracker(&self.orig_keyboard, self.switch_timeouts.len());
+        self.wheel_tracker.fill(WheelState::default());
+        self.reconfigure(settings, output)?;
+        self.start(output)
+    }
+
+    /// The keys are still held after the grab timeout. Grabbing now would leave them stuck: the system saw their
+    /// presses on the original keyboard and would never see the releases. So keep waiting, and say why.
+    fn warn_keys_still_held(&mut self) -> anyhow::Result<()> {
+        let held = self.orig_keyboard.get_key_state()?;
+        warn!(
+            "Keys still held on {} after {:?}, not grabbing it until they are released: {:?}",
+            self.label(),
+            self.grab_timeout,
+            held.iter().collect::<Vec<_>>()
+        );
+        self.grab_deadline = Some(Instant::now() + self.grab_timeout);
+        Ok(())
+    }
+
+    /// Brings the virtual device in line with the physical key state: presses the keys that are down but were never
+    /// forwarded, releases the forwarded keys that are up. The tracker entries of the corrected keys start over,
+    /// since the timing of the missed events is unknown. Returns the number of corrective events.
+    fn reconcile_keys(&mut self, output: &mut VirtualDevice) -> anyhow::Result<usize> {
+        let physical = self.orig_keyboard.get_key_state()?;
+        let corrections = key_corrections(&self.held_keys, &physical, SystemTime::now(), &mut self.tracker);
+        for &correction in &corrections {
+            forward(correction, output, &mut self.held_keys)?;
+        }
+
+        // Scan codes can't be queried, so their state is rebuilt from the next events
+        self.scan_tracker.clear();
+        self.frame_scan_code = None;
+        Ok(corrections.len())
+    }
+
+    /// The kernel buffer of the original keyboard overflowed (SYN_DROPPED) and some events were lost:
+    /// re-read the key and switch state and correct whatever the virtual device missed
+    fn recover_from_drop(&mut self, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        self.drop_recoveries = self.drop_recoveries.saturating_add(1);
+        let corrections = self.reconcile_keys(output)?;
+
+        // A pending switch change may have been bounced away in the lost events, so the physical state wins
+        self.switch_tracker = read_switch_tracker(&self.orig_keyboard, self.switch_timeouts.len());
+        self.sync_switches(output)?;
+
+        warn!(
+            "Events were dropped by {}; re-synchronized the key state with {corrections} corrective event(s)",
+            self.label()
+        );
+        Ok(())
+    }
+
+    /// The original keyboard vanished, possibly mid-press
+    pub(super) fn disconnect(&mut self, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        self.grabbed = false;
+        self.release_held_keys(output)
+    }
+
+    /// Releases the keys forwarded as pressed
+    fn release_held_keys(&mut self, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        for key_code in self.held_keys.iter() {
+            info!("Releasing held key {key_code:?}");
+            output.emit(&[*KeyEvent::new(key_code, 0)])?;
+        }
+        self.held_keys = AttributeSet::new();
+        Ok(())
+    }
+
+    /// Leaves the virtual device in a clean state and gives the original keyboard back
+    pub(super) fn stop(&mut self, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        self.release_held_keys(output)?;
+        if self.grabbed {
+            self.orig_keyboard.ungrab()?;
+            self.grabbed = false;
+            info!("Released the original keyboard: {}", self.label());
+        }
+        self.report_stats();
+        Ok(())
+    }
+
+    /// The nearest moment a pending switch change becomes stable or the grab timeout expires
+    pub(super) fn next_deadline(&self) -> Option<Instant> {
+        self.switch_tracker
+            .iter()
+            .filter_map(SwitchState::deadline)
+            .chain(self.grab_deadline)
+            .min()
+    }
+
+    fn label(&self) -> &str {
+        self.orig_keyboard
+            .physical_path()
+            .or(self.orig_keyboard.name())
+            .unwrap_or("Unnamed device")
+    }
+
+    pub(super) fn handle_timers(&mut self, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        let now = Instant::now();
+        if self.grab_deadline.is_some_and(|deadline| deadline <= now) {
+            self.warn_keys_still_held()?;
         }
+
+        for (index, state) in self.switch_tracker.iter_mut().enumerate() {
+            let Some(value) = state.settle(now) else {
+                continue;
+            };
+            let switch_code = SwitchCode::from_index(index);
+            debug!("Forwarding stable switch state {switch_code:?}={value}");
+            output.emit(&[*SwitchEvent::new(switch_code, value)])?;
+        }
+        Ok(())
     }
 
-    fn process_event_batch(&mut self) -> anyhow::Result<()> {
+    pub(super) fn process_event_batch(&mut self, output: &mut VirtualDevice) -> anyhow::Result<()> {
+        if !self.grabbed {
+            // Not grabbed yet, so the system still receives these events itself
+            for event in self.orig_keyboard.fetch_events()? {
+                trace!("Passing through {:?}", event);
+            }
+            if self.orig_keyboard.get_key_state()?.iter().next().is_none() {
+                return self.grab(output);
+            }
+            return Ok(());
+        }
+
         let mut filtered = false;
-        for orig_event in self.orig_keyboard.fetch_events()? {
+        let events = self.orig_keyboard.fetch_events()?.collect::<Vec<_>>();
+        for orig_event in events {
+            // After SYN_DROPPED everything up to the next SYN_REPORT is a partial frame and must be discarded,
+            // then the state is re-read from the device
+            if self.dropped {
+                trace!("Discarding {:?} after dropped events", orig_event);
+                if let EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, _) = orig_event.destructure() {
+                    self.dropped = false;
+                    self.recover_from_drop(output)?;
+                }
+                continue;
+            }
+            // MSC_SCAN precedes the key event it belongs to within the same SYN frame
+            match orig_event.destructure() {
+                EventSummary::Misc(_, MiscCode::MSC_SCAN, scan_code) => self.frame_scan_code = Some(scan_code as u32),
+                EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, _) => self.frame_scan_code = None,
+                EventSummary::Synchronization(_, SynchronizationCode::SYN_DROPPED, _) => {
+                    self.dropped = true;
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(scan_code) = self.frame_scan_code {
+                match should_filter_scan(orig_event, scan_code, &self.scan_timeouts, &mut self.scan_tracker) {
+                    Some(true) => {
+                        filtered = true;
+                        let count = self.scan_stats.entry(scan_code).or_default();
+                        *count = count.saturating_add(1);
+                        continue;
+                    }
+                    Some(false) => {
+                        forward(orig_event, output, &mut self.held_keys)?;
+                        continue;
+                    }
+                    None => {}
+                }
+            }
             if should_filter(orig_event, &self.key_timeouts, &mut self.tracker) {
                 filtered = true;
                 let index = orig_event.code() as usize;
                 self.stats[index] = self.stats[index].saturating_add(1);
                 continue;
             }
-            trace!("Forwarding {:?}", orig_event);
-            self.fake_keyboard.emit(&[orig_event])?;
+            match should_filter_switch(
+                orig_event,
+                Instant::now(),
+                &self.switch_timeouts,
+                &mut self.switch_tracker,
+            ) {
+                SwitchVerdict::Forward => {}
+                SwitchVerdict::Hold => continue,
+                SwitchVerdict::Bounce => {
+                    filtered = true;
+                    let index = orig_event.code() as usize;
+                    self.switch_stats[index] = self.switch_stats[index].saturating_add(1);
+                    continue;
+                }
+            }
+            if let Some(wheel_timeout) = self.wheel_timeout {
+                if should_filter_wheel(orig_event, wheel_timeout, &mut self.wheel_tracker) {
+                    filtered = true;
+                    let index = orig_event.code() as usize;
+                    self.wheel_stats[index] = self.wheel_stats[index].saturating_add(1);
+                    continue;
+                }
+            }
+            forward(orig_event, output, &mut self.held_keys)?;
         }
 
         if filtered {
@@ -117,6 +405,11 @@ impl KeyFilter {
             return;
         }
         self.last_stats_printed = Instant::now();
+        self.report_stats();
+    }
+
+    pub(super) fn report_stats(&self) {
+        let label = self.label();
         let mut parts = vec![];
 
         for (index, &count) in self.stats.iter().enumerate() {
@@ -127,14 +420,195 @@ impl KeyFilter {
             parts.push(format!("{key_code:?}:{index}x{count}"))
         }
 
-        if parts.is_empty() {
-            return;
+        if !parts.is_empty() {
+            info!("[{label}] Throttled: {}", parts.join(", "));
+        }
+
+        let scan_parts = self
+            .scan_stats
+            .iter()
+            .map(|(scan_code, count)| format!("{scan_code:#x}x{count}"))
+            .collect::<Vec<_>>();
+
+        if !scan_parts.is_empty() {
+            info!("[{label}] Throttled scan codes: {}", scan_parts.join(", "));
+        }
+
+        let mut switch_parts = vec![];
+        for (index, &count) in self.switch_stats.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let switch_code = SwitchCode::from_index(index);
+            switch_parts.push(format!("{switch_code:?}:{index}x{count}"))
         }
 
-        info!("Throttled: {}", parts.join(", "));
+        if !switch_parts.is_empty() {
+            info!("[{label}] Debounced switches: {}", switch_parts.join(", "));
+        }
+
+        let mut wheel_parts = vec![];
+        for (index, &count) in self.wheel_stats.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let axis = RelativeAxisCode(index as u16);
+            wheel_parts.push(format!("{axis:?}:{index}x{count}"))
+        }
+
+        if !wheel_parts.is_empty() {
+            info!("[{label}] Dropped wheel reversals: {}", wheel_parts.join(", "));
+        }
+
+        if self.drop_recoveries > 0 {
+            info!("[{label}] Recovered from dropped events: {}x", self.drop_recoveries);
+        }
     }
 }
 
+impl AsRawFd for KeyFilter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.orig_keyboard.as_raw_fd()
+    }
+}
+
+fn forward(
+    orig_event: InputEvent,
+    output: &mut VirtualDevice,
+    held_keys: &mut AttributeSetRef<KeyCode>,
+) -> std::io::Result<()> {
+    trace!("Forwarding {:?}", orig_event);
+    if let EventSummary::Key(_, key_code, key_state) = orig_event.destructure() {
+        if key_state == 0 {
+            held_keys.remove(key_code);
+        } else {
+            held_keys.insert(key_code);
+        }
+    }
+    output.emit(&[orig_event])
+}
+
+fn read_switch_tracker(orig_keyboard: &RawDevice, size: usize) -> Vec<SwitchState> {
+    let switch_state = orig_keyboard.get_switch_state().unwrap_or_else(|err| {
+        warn!("Failed to read the switch state: {err}");
+        AttributeSet::new()
+    });
+    (0..size)
+        .map(|index| SwitchState::new(switch_state.contains(SwitchCode::from_index(index)) as i32))
+        .collect()
+}
+
+/// Splits the rules into the key code, scan code and switch lookup tables, sized for the device
+fn build_lookup_tables(
+    settings: &FilterSettings,
+    orig_keyboard: &RawDevice,
+) -> (Vec<Option<Duration>>, Vec<ScanRangeTimeout>, Vec<Option<Duration>>) {
+    let max_keyboard_code = orig_keyboard
+        .supported_keys()
+        .iter()
+        .flat_map(|attribute_set| attribute_set.iter())
+        .map(|key_code| key_code.to_index())
+        .max()
+        .unwrap_or_default();
+    let mut key_rules = vec![];
+    let mut scan_timeouts = vec![];
+    for timeout_range in settings.timeouts.iter().cloned() {
+        match timeout_range {
+            TimeoutRange::Key(key_rule) => key_rules.push(key_rule),
+            TimeoutRange::Scan(scan_range_timeout) => scan_timeouts.push(scan_range_timeout),
+        }
+    }
+    let key_timeouts = build_key_timeouts(&key_rules, max_keyboard_code);
+
+    let max_switch_code = orig_keyboard
+        .supported_switches()
+        .iter()
+        .flat_map(|attribute_set| attribute_set.iter())
+        .map(|switch_code| switch_code.to_index())
+        .max()
+        .unwrap_or_default();
+    let switch_timeouts = build_timeouts("Switch", settings.switch_timeouts.clone(), max_switch_code);
+
+    (key_timeouts, scan_timeouts, switch_timeouts)
+}
+
+/// The most specific rule of the first layer covering a key wins; conflicting and useless rules are reported
+fn build_key_timeouts(key_rules: &[KeyRule], max_device_code: usize) -> Vec<Option<Duration>> {
+    let max_requested_code = key_rules
+        .iter()
+        .filter_map(|key_rule| key_rule.keys.max_code())
+        .map(usize::from)
+        .max()
+        .unwrap_or_default();
+
+    let required_size = if key_rules.is_empty() {
+        0
+    } else {
+        max_device_code.min(max_requested_code) + 1
+    };
+    let resolutions = resolve_key_rules(key_rules, required_size);
+
+    for (index, key_rule) in key_rules.iter().enumerate() {
+        if !resolutions.iter().flatten().any(|resolution| resolution.rule == index) {
+            warn!(
+                "Key rule {} applies to no key: the keys are out of device's range ({} codes) or covered by rules \
+                 that take precedence",
+                key_rule.source, max_device_code
+            );
+        }
+    }
+    for ((winner, loser), count) in collect_conflicts(&resolutions) {
+        warn!(
+            "Key rules {} and {} are equally specific and overlap on {count} key(s); using the first one",
+            key_rules[winner].source, key_rules[loser].source
+        );
+    }
+
+    resolutions
+        .into_iter()
+        .map(|resolution| resolution.map(|resolution| key_rules[resolution.rule].timeout))
+        .collect()
+}
+
+fn build_timeouts(kind: &str, timeouts: Vec<KeyRangeTimeout>, max_device_code: usize) -> Vec<Option<Duration>> {
+    let max_requested_code = timeouts
+        .iter()
+        .map(|key_range_timeout| (*key_range_timeout.range.end()) as usize)
+        .max()
+        .unwrap_or_default();
+
+    let required_size = if timeouts.is_empty() {
+        0
+    } else {
+        max_device_code.min(max_requested_code) + 1
+    };
+    let mut code_timeouts = vec![None; required_size];
+
+    for key_range_timeout in timeouts {
+        for code in key_range_timeout.range.clone().map(usize::from) {
+            if code >= required_size {
+                warn!(
+                    "{kind} code {} from provided range {:?} is out of device's range: device has {} codes",
+                    code, key_range_timeout, max_device_code
+                );
+                break;
+            }
+
+            if let Some(timeout) = code_timeouts[code] {
+                warn!(
+                    "{kind} code {:?} is already throttled with timeout {:?}, ignoring the new timeout {:?}",
+                    code, timeout, key_range_timeout.timeout
+                );
+                continue;
+            }
+
+            code_timeouts[code] = Some(key_range_timeout.timeout);
+        }
+    }
+
+    code_timeouts
+}
+
 fn should_filter(orig_event: InputEvent, key_timeouts: &[Option<Duration>], tracker: &mut [KeyState]) -> bool {
     let (key_code, key_state) = match orig_event.destructure() {
         EventSummary::Key(_, key_code, key_state) => (key_code, key_state),
@@ -145,9 +619,72 @@ fn should_filter(orig_event: InputEvent, key_timeouts: &[Option<Duration>], trac
         return false;
     };
 
-    let now = orig_event.timestamp();
     let state = &mut tracker[key_code.to_index()];
+    should_throttle(key_code, key_state, orig_event.timestamp(), max_duration, state)
+}
 
+/// The events that bring the forwarded keys in line with the physical ones: releases of the keys that are up, then
+/// presses of the keys that are down but were never forwarded. The tracker entries of those keys start over.
+fn key_corrections(
+    held_keys: &AttributeSetRef<KeyCode>,
+    physical: &AttributeSetRef<KeyCode>,
+    now: SystemTime,
+    tracker: &mut [KeyState],
+) -> Vec<InputEvent> {
+    let mut corrections = vec![];
+    for key_code in held_keys.iter().filter(|&key_code| !physical.contains(key_code)) {
+        debug!("Key {key_code:?} was released while events were lost; releasing it");
+        corrections.push(*KeyEvent::new(key_code, 0));
+        if let Some(state) = tracker.get_mut(key_code.to_index()) {
+            *state = KeyState::default();
+        }
+    }
+    for key_code in physical.iter().filter(|&key_code| !held_keys.contains(key_code)) {
+        debug!("Key {key_code:?} is held but was never forwarded; pressing it");
+        corrections.push(*KeyEvent::new(key_code, 1));
+        if let Some(state) = tracker.get_mut(key_code.to_index()) {
+            *state = KeyState::Down(now);
+        }
+    }
+    corrections
+}
+
+fn scan_timeout(scan_timeouts: &[ScanRangeTimeout], scan_code: u32) -> Option<Duration> {
+    scan_timeouts
+        .iter()
+        .find(|scan_range_timeout| scan_range_timeout.range.contains(&scan_code))
+        .map(|scan_range_timeout| scan_range_timeout.timeout)
+}
+
+/// Returns `None` if the scan code is not covered by any scan code range, so the key code rules apply
+fn should_filter_scan(
+    orig_event: InputEvent,
+    scan_code: u32,
+    scan_timeouts: &[ScanRangeTimeout],
+    tracker: &mut HashMap<u32, KeyState>,
+) -> Option<bool> {
+    let EventSummary::Key(_, key_code, key_state) = orig_event.destructure() else {
+        return None;
+    };
+    let max_duration = scan_timeout(scan_timeouts, scan_code)?;
+
+    let state = tracker.entry(scan_code).or_default();
+    Some(should_throttle(
+        key_code,
+        key_state,
+        orig_event.timestamp(),
+        max_duration,
+        state,
+    ))
+}
+
+fn should_throttle(
+    key_code: KeyCode,
+    key_state: i32,
+    now: SystemTime,
+    max_duration: Duration,
+    state: &mut KeyState,
+) -> bool {
     let since_previous = match state.duration_since(&now) {
         Ok(value) => value,
         Err(err) => {
@@ -206,9 +743,86 @@ fn should_filter(orig_event: InputEvent, key_timeouts: &[Option<Duration>], trac
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+enum SwitchVerdict {
+    /// Not a debounced switch event
+    Forward,
+    /// The switch left (or repeated) its stable state; a change is forwarded once it stays unchanged for the timeout
+    Hold,
+    /// The switch returned to its stable state before the timeout; the pending change is discarded
+    Bounce,
+}
+
+fn should_filter_switch(
+    orig_event: InputEvent,
+    now: Instant,
+    switch_timeouts: &[Option<Duration>],
+    tracker: &mut [SwitchState],
+) -> SwitchVerdict {
+    let (switch_code, value) = match orig_event.destructure() {
+        EventSummary::Switch(_, switch_code, value) => (switch_code, value),
+        _ => return SwitchVerdict::Forward,
+    };
+    let Some(&Some(max_duration)) = switch_timeouts.get(switch_code.to_index()) else {
+        debug!("Switch code {switch_code:?} cannot be debounced");
+        return SwitchVerdict::Forward;
+    };
+
+    let state = &mut tracker[switch_code.to_index()];
+    if value == state.stable() {
+        if !state.is_pending() {
+            return SwitchVerdict::Hold;
+        }
+        debug!("Debounced switch {switch_code:?}: returned to {value}");
+        state.cancel();
+        return SwitchVerdict::Bounce;
+    }
+
+    // Every change restarts the window, so only a state that holds for the whole timeout gets through
+    state.hold(value, now + max_duration);
+    SwitchVerdict::Hold
+}
+
+fn should_filter_wheel(orig_event: InputEvent, max_duration: Duration, tracker: &mut [WheelState]) -> bool {
+    let (axis, value) = match orig_event.destructure() {
+        EventSummary::RelativeAxis(_, axis, value) if WHEEL_AXES.contains(&axis) => (axis, value),
+        _ => return false,
+    };
+
+    let direction = value.signum();
+    if direction == 0 {
+        return false;
+    }
+
+    let now = orig_event.timestamp();
+    let state = &mut tracker[axis.0 as usize];
+
+    let since_previous = match state.duration_since(&now) {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Clock drift detected; skipping wheel filtering: {err}");
+            return false;
+        }
+    };
+
+    // A reversed tick does not refresh the state, so a real change of the scroll direction
+    // gets through as soon as the window since the last accepted tick is over
+    if state.direction() != 0 && state.direction() != direction && since_previous < max_duration {
+        debug!(
+            "Dropped reversed wheel tick {axis:?}:{value}; elapsed: {}",
+            since_previous.as_millis()
+        );
+        return true;
+    }
+
+    *state = WheelState::new(direction, now);
+    false
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use evdev::EventType;
     use std::time::SystemTime;
 
     const DOWN: i32 = 1;
@@ -268,6 +882,61 @@ mod tests {
         );
     }
 
+    fn key_set(key_codes: &[KeyCode]) -> AttributeSet<KeyCode> {
+        key_codes.iter().copied().collect()
+    }
+
+    #[test]
+    fn test_key_corrections_press() {
+        let (_, mut tracker, _) = prepare(DOWN);
+        let now = SystemTime::now();
+        let corrections = key_corrections(&key_set(&[]), &key_set(&[KeyCode::KEY_ESC]), now, &mut tracker);
+        assert_eq!(
+            corrections,
+            [*KeyEvent::new(KeyCode::KEY_ESC, DOWN)],
+            "Should press the key held but never forwarded"
+        );
+        assert!(
+            matches!(tracker[1], KeyState::Down(time) if time == now),
+            "Should track the key as down"
+        );
+    }
+
+    #[test]
+    fn test_key_corrections_release() {
+        let (_, mut tracker, _) = prepare(UP);
+        tracker[1] = KeyState::Down(SystemTime::now());
+        let corrections = key_corrections(
+            &key_set(&[KeyCode::KEY_ESC]),
+            &key_set(&[]),
+            SystemTime::now(),
+            &mut tracker,
+        );
+        assert_eq!(
+            corrections,
+            [*KeyEvent::new(KeyCode::KEY_ESC, UP)],
+            "Should release the key released while events were lost"
+        );
+        assert!(
+            matches!(tracker[1], KeyState::Up(time) if time == SystemTime::UNIX_EPOCH),
+            "Should reset the tracker"
+        );
+    }
+
+    #[test]
+    fn test_key_corrections_none() {
+        let (_, mut tracker, _) = prepare(DOWN);
+        let since = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        tracker[1] = KeyState::Down(since);
+        let held = key_set(&[KeyCode::KEY_ESC]);
+        let corrections = key_corrections(&held, &held, SystemTime::now(), &mut tracker);
+        assert!(
+            corrections.is_empty(),
+            "Should not correct anything when the states agree"
+        );
+        assert_eq!(tracker[1].time(), since, "Should NOT update the tracker");
+    }
+
     #[test]
     fn test_should_filter_down_up() {
         let (input_event, mut tracker, key_timeouts) = prepare(UP);
@@ -282,4 +951,133 @@ mod tests {
             "Should filter the second down event"
         );
     }
+
+    fn prepare_wheel(value: i32) -> (InputEvent, Vec<WheelState>, Duration) {
+        let input_event = InputEvent::new_now(EventType::RELATIVE.0, RelativeAxisCode::REL_WHEEL.0, value);
+        let tracker = vec![WheelState::default(); RelativeAxisCode::REL_HWHEEL_HI_RES.0 as usize + 1];
+        (input_event, tracker, Duration::from_millis(10))
+    }
+
+    #[test]
+    fn test_should_filter_wheel_reversal() {
+        let (input_event, mut tracker, timeout) = prepare_wheel(-1);
+        tracker[RelativeAxisCode::REL_WHEEL.0 as usize] = WheelState::new(1, input_event.timestamp());
+        assert!(
+            should_filter_wheel(input_event, timeout, &mut tracker),
+            "Should filter a reversed tick within the timeout"
+        );
+        assert_eq!(
+            tracker[RelativeAxisCode::REL_WHEEL.0 as usize].direction(),
+            1,
+            "Should NOT update the tracker"
+        );
+    }
+
+    #[test]
+    fn test_should_filter_wheel_same_direction() {
+        let (input_event, mut tracker, timeout) = prepare_wheel(1);
+        assert!(
+            !should_filter_wheel(input_event, timeout, &mut tracker),
+            "Should not filter the first tick"
+        );
+        assert_ne!(
+            tracker[RelativeAxisCode::REL_WHEEL.0 as usize].time(),
+            SystemTime::UNIX_EPOCH,
+            "Should update the tracker"
+        );
+        assert!(
+            !should_filter_wheel(input_event, timeout, &mut tracker),
+            "Should not filter the second tick in the same direction"
+        );
+    }
+
+    #[test]
+    fn test_should_filter_wheel_reversal_after_timeout() {
+        let (input_event, mut tracker, timeout) = prepare_wheel(-1);
+        tracker[RelativeAxisCode::REL_WHEEL.0 as usize] = WheelState::new(1, SystemTime::UNIX_EPOCH);
+        assert!(
+            !should_filter_wheel(input_event, timeout, &mut tracker),
+            "Should not filter a reversed tick after the timeout"
+        );
+        assert_eq!(tracker[RelativeAxisCode::REL_WHEEL.0 as usize].direction(), -1);
+    }
+
+    fn prepare_switch(value: i32) -> (InputEvent, Vec<SwitchState>, [Option<Duration>; 2]) {
+        let input_event = *SwitchEvent::new_now(SwitchCode::SW_TABLET_MODE, value);
+        let tracker = vec![SwitchState::default(); 2];
+        let switch_timeouts = [Some(Duration::from_millis(10)); 2];
+        (input_event, tracker, switch_timeouts)
+    }
+
+    #[test]
+    fn test_should_filter_switch_stable_change() {
+        let (input_event, mut tracker, switch_timeouts) = prepare_switch(1);
+        let now = Instant::now();
+        assert_eq!(
+            should_filter_switch(input_event, now, &switch_timeouts, &mut tracker),
+            SwitchVerdict::Hold,
+            "Should hold the change until it is stable"
+        );
+        assert_eq!(tracker[1].settle(now), None, "Should not settle before the timeout");
+        assert_eq!(
+            tracker[1].settle(now + Duration::from_millis(10)),
+            Some(1),
+            "Should settle after the timeout"
+        );
+        assert_eq!(tracker[1].stable(), 1);
+    }
+
+    #[test]
+    fn test_should_filter_switch_bounce() {
+        let (input_event, mut tracker, switch_timeouts) = prepare_switch(1);
+        let now = Instant::now();
+        should_filter_switch(input_event, now, &switch_timeouts, &mut tracker);
+        let back = *SwitchEvent::new_now(SwitchCode::SW_TABLET_MODE, 0);
+        assert_eq!(
+            should_filter_switch(back, now, &switch_timeouts, &mut tracker),
+            SwitchVerdict::Bounce,
+            "Should discard the change reverted within the timeout"
+        );
+        assert_eq!(tracker[1].settle(now + Duration::from_secs(1)), None);
+        assert_eq!(tracker[1].stable(), 0);
+    }
+
+    #[test]
+    fn test_should_filter_switch_without_timeout() {
+        let (input_event, mut tracker, _) = prepare_switch(1);
+        assert_eq!(
+            should_filter_switch(input_event, Instant::now(), &[None, None], &mut tracker),
+            SwitchVerdict::Forward
+        );
+    }
+
+    #[test]
+    fn test_should_filter_scan() {
+        let (input_event, _, _) = prepare(DOWN);
+        let scan_timeouts = [ScanRangeTimeout {
+            range: 0x70004..=0x70005,
+            timeout: Duration::from_millis(10),
+        }];
+        let mut tracker = HashMap::new();
+        assert_eq!(
+            should_filter_scan(input_event, 0x70004, &scan_timeouts, &mut tracker),
+            Some(false),
+            "Should not filter the first down event"
+        );
+        assert_eq!(
+            should_filter_scan(input_event, 0x70005, &scan_timeouts, &mut tracker),
+            Some(false),
+            "Should track the same key code with another scan code separately"
+        );
+        assert_eq!(
+            should_filter_scan(input_event, 0x70004, &scan_timeouts, &mut tracker),
+            Some(true),
+            "Should filter the second down event"
+        );
+        assert_eq!(
+            should_filter_scan(input_event, 0x70006, &scan_timeouts, &mut tracker),
+            None,
+            "Should leave scan codes out of range to the key code rules"
+        );
+    }
 }
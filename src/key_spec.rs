@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use evdev::KeyCode;
+
+use crate::cmd::parse_timeout;
+
+/// The highest key code the kernel defines (`KEY_MAX`)
+pub(super) const KEY_MAX: u16 = 0x2ff;
+
+const LETTERS: &[RangeInclusive<u16>] = &[
+    KeyCode::KEY_Q.0..=KeyCode::KEY_P.0,
+    KeyCode::KEY_A.0..=KeyCode::KEY_L.0,
+    KeyCode::KEY_Z.0..=KeyCode::KEY_M.0,
+];
+
+const DIGITS: &[RangeInclusive<u16>] = &[KeyCode::KEY_1.0..=KeyCode::KEY_0.0];
+
+const MODIFIERS: &[RangeInclusive<u16>] = &[
+    KeyCode::KEY_LEFTCTRL.0..=KeyCode::KEY_LEFTCTRL.0,
+    KeyCode::KEY_LEFTSHIFT.0..=KeyCode::KEY_LEFTSHIFT.0,
+    KeyCode::KEY_RIGHTSHIFT.0..=KeyCode::KEY_RIGHTSHIFT.0,
+    KeyCode::KEY_LEFTALT.0..=KeyCode::KEY_LEFTALT.0,
+    KeyCode::KEY_RIGHTCTRL.0..=KeyCode::KEY_RIGHTCTRL.0,
+    KeyCode::KEY_RIGHTALT.0..=KeyCode::KEY_RIGHTALT.0,
+    KeyCode::KEY_LEFTMETA.0..=KeyCode::KEY_RIGHTMETA.0,
+];
+
+const ARROWS: &[RangeInclusive<u16>] = &[
+    KeyCode::KEY_UP.0..=KeyCode::KEY_UP.0,
+    KeyCode::KEY_LEFT.0..=KeyCode::KEY_RIGHT.0,
+    KeyCode::KEY_DOWN.0..=KeyCode::KEY_DOWN.0,
+];
+
+const NUMPAD: &[RangeInclusive<u16>] = &[
+    KeyCode::KEY_KPASTERISK.0..=KeyCode::KEY_KPASTERISK.0,
+    KeyCode::KEY_NUMLOCK.0..=KeyCode::KEY_NUMLOCK.0,
+    KeyCode::KEY_KP7.0..=KeyCode::KEY_KPDOT.0,
+    KeyCode::KEY_KPENTER.0..=KeyCode::KEY_KPENTER.0,
+    KeyCode::KEY_KPSLASH.0..=KeyCode::KEY_KPSLASH.0,
+    KeyCode::KEY_KPEQUAL.0..=KeyCode::KEY_KPPLUSMINUS.0,
+    KeyCode::KEY_KPCOMMA.0..=KeyCode::KEY_KPCOMMA.0,
+];
+
+const FUNCTION_KEYS: &[RangeInclusive<u16>] = &[
+    KeyCode::KEY_F1.0..=KeyCode::KEY_F10.0,
+    KeyCode::KEY_F11.0..=KeyCode::KEY_F12.0,
+    KeyCode::KEY_F13.0..=KeyCode::KEY_F24.0,
+];
+
+/// Letters and digits, which every full keyboard has, unlike the media key nodes of the same keyboard
+pub(super) fn alphanumeric_codes() -> impl Iterator<Item = u16> {
+    LETTERS.iter().chain(DIGITS).flat_map(|range| range.clone())
+}
+
+/// Named groups usable in key specifications, besides `all`
+pub(super) const GROUPS: &[(&str, &[RangeInclusive<u16>])] = &[
+    ("letters", LETTERS),
+    ("digits", DIGITS),
+    ("modifiers", MODIFIERS),
+    ("arrows", ARROWS),
+    ("numpad", NUMPAD),
+    ("function", FUNCTION_KEYS),
+];
+
+/// The union of the included key codes minus the excluded ones
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(super) struct KeySpec {
+    included: Vec<RangeInclusive<u16>>,
+    excluded: Vec<RangeInclusive<u16>>,
+}
+
+impl KeySpec {
+    pub(super) fn contains(&self, code: u16) -> bool {
+        self.included.iter().any(|range| range.contains(&code))
+            && !self.excluded.iter().any(|range| range.contains(&code))
+    }
+
+    /// The number of covered key codes; the fewer, the more specific the rule is
+    pub(super) fn len(&self) -> usize {
+        (0..=KEY_MAX).filter(|&code| self.contains(code)).count()
+    }
+
+    pub(super) fn codes(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..=KEY_MAX).filter(|&code| self.contains(code))
+    }
+
+    pub(super) fn max_code(&self) -> Option<u16> {
+        (0..=KEY_MAX).rev().find(|&code| self.contains(code))
+    }
+
+    /// The covered codes above `max`, as inclusive ranges; `all` stands for whatever keys there are, so it never
+    /// goes beyond
+    pub(super) fn ranges_above(&self, max: u16) -> Vec<RangeInclusive<u16>> {
+        let mut codes = self
+            .included
+            .iter()
+            .filter(|range| **range != (0..=KEY_MAX))
+            .flat_map(|range| range.clone())
+            .filter(|&code| code > max && !self.excluded.iter().any(|range| range.contains(&code)))
+            .collect::<Vec<_>>();
+        codes.sort_unstable();
+        codes.dedup();
+
+        let mut ranges: Vec<RangeInclusive<u16>> = vec![];
+        for code in codes {
+            match ranges.last_mut() {
+                Some(range) if *range.end() + 1 == code => *range = *range.start()..=code,
+                _ => ranges.push(code..=code),
+            }
+        }
+        ranges
+    }
+}
+
+/// A timeout for a set of keys, i.e. `KEY_Q..KEY_P:70ms`, `letters,!KEY_Q:50ms` or the numeric `30:40:70`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct KeyRule {
+    pub(super) keys: KeySpec,
+    pub(super) timeout: Duration,
+    /// The rule as it was given, to tell where a timeout came from
+    pub(super) source: Arc<str>,
+    /// Rules of a lower layer win however specific the others are: the command line, then a profile, then the
+    /// top-level rules of the config files
+    pub(super) layer: usize,
+}
+
+/// Which rule applies to a key; equally specific rules with other timeouts are conflicts, and the first one wins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct KeyResolution {
+    pub(super) rule: usize,
+    pub(super) conflicts: Vec<usize>,
+}
+
+pub(super) fn parse_key_rule(raw: &str) -> Result<KeyRule, String> {
+    let Some((spec, timeout)) = raw.rsplit_once(':') else {
+        return Err(format!("Invalid key rule {raw}: expected <keys>:<timeout>"));
+    };
+    let timeout = parse_timeout(timeout)?;
+
+    // The original numeric format: <start>:<end>:<timeout>
+    let keys = if let Some((start, end)) = spec.split_once(':') {
+        let range = match (start.parse::<u16>(), end.parse::<u16>()) {
+            (Ok(start), Ok(end)) => start..=end,
+            (Err(err), _) | (_, Err(err)) => return Err(format!("Invalid key range {raw}: {}", err)),
+        };
+        if range.is_empty() {
+            return Err(format!("Invalid empty range set for key range {}", raw));
+        }
+        KeySpec {
+            included: vec![range],
+            excluded: vec![],
+        }
+    } else {
+        parse_key_spec(spec).map_err(|err| format!("Invalid key rule {raw}: {err}"))?
+    };
+
+    Ok(KeyRule {
+        keys,
+        timeout,
+        source: raw.into(),
+        layer: 0,
+    })
+}
+
+/// Comma-separated items, each optionally negated with `!`: `all`, a group name, a key name (`KEY_A`, `BTN_LEFT`),
+/// a key code, or an inclusive range of either (`KEY_Q..KEY_P`, `30..40`). Ranges go by code, so a range between two
+/// keys of a group must not run across other keys: `KEY_A..KEY_Z` is rejected in favor of `letters`
+pub(super) fn parse_key_spec(raw: &str) -> Result<KeySpec, String> {
+    let mut spec = KeySpec::default();
+    for item in raw.split(',').map(str::trim) {
+        let (ranges, excluded) = match item.strip_prefix('!') {
+            Some(item) => (parse_key_item(item)?, true),
+            None => (parse_key_item(item)?, false),
+        };
+        if excluded {
+            spec.excluded.extend(ranges);
+        } else {
+            spec.included.extend(ranges);
+        }
+    }
+
+    if spec.included.is_empty() {
+        return Err("no keys included, i.e. use all,!KEY_SPACE".to_string());
+    }
+    Ok(spec)
+}
+
+fn parse_key_item(raw: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    if raw == "all" {
+        return Ok(vec![0..=KEY_MAX]);
+    }
+    if let Some((_, ranges)) = GROUPS.iter().find(|(name, _)| *name == raw) {
+        return Ok(ranges.to_vec());
+    }
+
+    let range = match raw.split_once("..") {
+        Some((start, end)) => {
+            let range = parse_key_code(start)?..=parse_key_code(end)?;
+            let is_name = |raw: &str| !raw.starts_with(|char: char| char.is_ascii_digit());
+            if is_name(start) && is_name(end) {
+                check_group_run(raw, &range)?;
+            }
+            range
+        }
+        None => {
+            let code = parse_key_code(raw)?;
+            code..=code
+        }
+    };
+    if range.is_empty() {
+        return Err(format!("empty key range {raw}"));
+    }
+    Ok(vec![range])
+}
+
+/// A range between two keys of the same group must stay within the group: the codes of `KEY_A..KEY_Z` skip the
+/// top and bottom letter rows and take in `KEY_LEFTSHIFT` and punctuation instead
+fn check_group_run(raw: &str, range: &RangeInclusive<u16>) -> Result<(), String> {
+    let in_group = |ranges: &[RangeInclusive<u16>], code: u16| ranges.iter().any(|range| range.contains(&code));
+    let Some((name, ranges)) = GROUPS
+        .iter()
+        .find(|(_, ranges)| in_group(ranges, *range.start()) && in_group(ranges, *range.end()))
+    else {
+        return Ok(());
+    };
+
+    let strays = range
+        .clone()
+        .filter(|&code| !in_group(ranges, code))
+        .map(|code| format!("{:?}", KeyCode::new(code)))
+        .collect::<Vec<_>>();
+    if strays.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "key range {raw} goes by code and takes in keys other than {name}: {}; use {name}, a list, or exclusions instead",
+        strays.join(", ")
+    ))
+}
+
+fn parse_key_code(raw: &str) -> Result<u16, String> {
+    let code = if let Some(hex) = raw.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).map_err(|err| format!("invalid key code {raw}: {err}"))?
+    } else if raw.starts_with(|char: char| char.is_ascii_digit()) {
+        raw.parse::<u16>()
+            .map_err(|err| format!("invalid key code {raw}: {err}"))?
+    } else {
+        let groups = GROUPS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+        KeyCode::from_str(raw)
+            .map_err(|_| format!("unknown key {raw}; expected a key name, a code, all, or one of: {groups}"))?
+            .code()
+    };
+
+    if code > KEY_MAX {
+        return Err(format!("key code {raw} is above KEY_MAX ({KEY_MAX:#x})"));
+    }
+    Ok(code)
+}
+
+/// For every code below `size`, the rule that applies to it: the lowest layer covering the code decides, and within
+/// it the most specific rule (covering the fewest keys) wins, so `KEY_SPACE:20` overrides `all:70` regardless of the
+/// order, but not `all:70` given on the command line
+pub(super) fn resolve_key_rules(rules: &[KeyRule], size: usize) -> Vec<Option<KeyResolution>> {
+    let ranks = rules
+        .iter()
+        .map(|rule| (rule.layer, rule.keys.len()))
+        .collect::<Vec<_>>();
+
+    (0..size)
+        .map(|code| {
+            let mut matching = (0..rules.len())
+                .filter(|&index| rules[index].keys.contains(code as u16))
+                .collect::<Vec<_>>();
+            // Stable, so the order of the rules decides among equally specific ones
+            matching.sort_by_key(|&index| ranks[index]);
+            let (&rule, rest) = matching.split_first()?;
+
+            let conflicts = rest
+                .iter()
+                .copied()
+                .filter(|&index| ranks[index] == ranks[rule] && rules[index].timeout != rules[rule].timeout)
+                .collect();
+            Some(KeyResolution { rule, conflicts })
+        })
+        .collect()
+}
+
+/// Pairs of conflicting rules (winner, loser) with the number of keys they conflict on
+pub(super) fn collect_conflicts(resolutions: &[Option<KeyResolution>]) -> BTreeMap<(usize, usize), usize> {
+    let mut conflicts = BTreeMap::new();
+    for resolution in resolutions.iter().flatten() {
+        for &loser in &resolution.conflicts {
+            *conflicts.entry((resolution.rule, loser)).or_default() += 1;
+        }
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(raw: &str) -> KeyRule {
+        parse_key_rule(raw).unwrap()
+    }
+
+    #[test]
+    fn test_parse_key_rule() {
+        let numeric = rule("30:40:70");
+        assert_eq!(numeric.timeout, Duration::from_millis(70));
+        assert_eq!(numeric.keys.len(), 11);
+        assert!(numeric.keys.contains(30) && numeric.keys.contains(40) && !numeric.keys.contains(41));
+
+        let row = rule("KEY_Q..KEY_P:70ms");
+        assert_eq!(row.keys.len(), 10);
+        assert!(row.keys.contains(KeyCode::KEY_Q.0) && row.keys.contains(KeyCode::KEY_P.0));
+        assert_eq!(rule("KEY_ESC..KEY_BACKSPACE:70").keys.len(), 14);
+        assert_eq!(rule("30..44:70").keys.len(), 15);
+        // Codes 30..=44 miss 16 letters and take in KEY_LEFTSHIFT and punctuation
+        let err = parse_key_rule("KEY_A..KEY_Z:70ms").unwrap_err();
+        assert!(err.contains("KEY_LEFTSHIFT") && err.contains("use letters"), "{err}");
+        assert!(parse_key_rule("KEY_F1..KEY_F12:70").is_err());
+
+        let list = rule("KEY_A,KEY_SPACE,BTN_LEFT:1s");
+        assert_eq!(list.timeout, Duration::from_secs(1));
+        assert_eq!(list.keys.len(), 3);
+        assert!(list.keys.contains(KeyCode::BTN_LEFT.0));
+
+        let excluded = rule("all,!KEY_SPACE:500us");
+        assert_eq!(excluded.timeout, Duration::from_micros(500));
+        assert_eq!(excluded.keys.len(), KEY_MAX as usize);
+        assert!(!excluded.keys.contains(KeyCode::KEY_SPACE.0));
+
+        let group = rule("letters,!KEY_Q:50");
+        assert_eq!(group.keys.len(), 25);
+        assert_eq!(rule("digits:50").keys.len(), 10);
+        assert_eq!(rule("arrows:50").keys.len(), 4);
+        assert_eq!(rule("modifiers:50").keys.len(), 8);
+        assert_eq!(rule("function:50").keys.len(), 24);
+        assert!(rule("numpad:50").keys.contains(KeyCode::KEY_KP5.0));
+        assert!(rule("0x10..0x19:50").keys.contains(KeyCode::KEY_P.0));
+
+        assert!(parse_key_rule("KEY_A").is_err());
+        assert!(parse_key_rule("KEY_A:0").is_err());
+        assert!(parse_key_rule("KEY_A:70h").is_err());
+        assert!(parse_key_rule("KEY_FOOBAR:70").is_err());
+        assert!(parse_key_rule("KEY_Z..KEY_A:70").is_err());
+        assert!(parse_key_rule("!KEY_SPACE:70").is_err());
+        assert!(parse_key_rule("0x300:70").is_err());
+        assert!(parse_key_rule("40:30:70").is_err());
+        assert!(parse_key_rule("1:2:3:4").is_err());
+    }
+
+    #[test]
+    fn test_resolve_key_rules() {
+        let rules = [
+            rule("all:70"),
+            rule("KEY_SPACE:20"),
+            rule("letters:30"),
+            rule("KEY_A..KEY_L:40"),
+        ];
+        let resolutions = resolve_key_rules(&rules, KeyCode::KEY_SPACE.0 as usize + 1);
+
+        let applied = |key_code: KeyCode| resolutions[key_code.0 as usize].as_ref().unwrap().rule;
+        assert_eq!(applied(KeyCode::KEY_ESC), 0);
+        assert_eq!(applied(KeyCode::KEY_SPACE), 1);
+        assert_eq!(applied(KeyCode::KEY_Q), 2);
+        assert_eq!(applied(KeyCode::KEY_A), 3);
+        assert!(collect_conflicts(&resolutions).is_empty());
+
+        let rules = [rule("KEY_A:70"), rule("KEY_B:70"), rule("KEY_A:20"), rule("KEY_A:70")];
+        let resolutions = resolve_key_rules(&rules, KeyCode::KEY_B.0 as usize + 1);
+        assert_eq!(
+            resolutions[KeyCode::KEY_A.0 as usize],
+            Some(KeyResolution {
+                rule: 0,
+                conflicts: vec![2],
+            })
+        );
+        assert_eq!(collect_conflicts(&resolutions), BTreeMap::from([((0, 2), 1)]));
+        assert_eq!(resolutions[0], None);
+
+        // A broad rule of a lower layer wins over a narrow one of a higher layer
+        let mut file_rule = rule("KEY_SPACE:20");
+        file_rule.layer = 1;
+        let rules = [rule("all:30"), file_rule];
+        let resolutions = resolve_key_rules(&rules, KeyCode::KEY_SPACE.0 as usize + 1);
+        assert_eq!(resolutions[KeyCode::KEY_SPACE.0 as usize].as_ref().unwrap().rule, 0);
+        assert!(collect_conflicts(&resolutions).is_empty());
+    }
+}
@@ -1,31 +1,44 @@
+use std::process::ExitCode;
+
 use clap::Parser;
 use env_logger::Env;
 
 use crate::cmd::Cli;
 use crate::traits::Execute;
 
+mod check_config;
 mod cmd;
+mod config;
 mod device_wrapper;
 mod display;
+mod error;
+mod event_loop;
 mod execute;
+mod hotplug;
+mod identify;
 mod key_filter;
+mod key_spec;
 mod key_state;
+mod shutdown;
+mod switch_state;
 mod traits;
+mod udev;
+mod wheel_state;
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
     let env = Env::default()
         .filter_or("LOG_LEVEL", "info")
         .write_style_or("LOG_STYLE", "always");
     env_logger::init_from_env(env);
+    shutdown::install_panic_hook();
 
     match Cli::try_parse() {
-        Ok(args) => {
-            args.execute()?;
-        }
+        Ok(args) => match args.execute() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => error::report(&err),
+        },
         Err(err) => {
             err.exit();
         }
     }
-
-    Ok(())
 }
@@ -0,0 +1,48 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsFd;
+use std::sync::{Mutex, PoisonError};
+
+use evdev::uinput::VirtualDevice;
+use evdev::{InputEvent, KeyCode, KeyEvent, SynchronizationCode, SynchronizationEvent};
+
+/// Key codes are below `KEY_CNT` (`KEY_MAX + 1`)
+const KEY_CNT: u16 = 0x300;
+
+/// Duplicated descriptors of the virtual devices; the panic hook can't reach the event loop owning them
+static PANIC_OUTPUTS: Mutex<Vec<File>> = Mutex::new(Vec::new());
+
+/// Makes the panic hook release every key on the virtual device
+pub(super) fn release_on_panic(output: &VirtualDevice) -> anyhow::Result<()> {
+    let file = File::from(output.as_fd().try_clone_to_owned()?);
+    PANIC_OUTPUTS.lock().unwrap_or_else(PoisonError::into_inner).push(file);
+    Ok(())
+}
+
+pub(super) fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        // Never block in a panic hook: the panic may have happened during the registration
+        if let Ok(outputs) = PANIC_OUTPUTS.try_lock() {
+            for mut output in outputs.iter() {
+                if let Err(err) = release_all_keys(&mut output) {
+                    eprintln!("Failed to release keys on a virtual device: {err}");
+                }
+            }
+        }
+        default_hook(panic_info);
+    }));
+}
+
+/// The input core only passes through releases of the keys that are down, so releasing everything is safe
+fn release_all_keys(output: &mut impl Write) -> std::io::Result<()> {
+    let mut events = (0..KEY_CNT)
+        .map(|code| *KeyEvent::new(KeyCode(code), 0))
+        .collect::<Vec<InputEvent>>();
+    events.push(*SynchronizationEvent::new(SynchronizationCode::SYN_REPORT, 0));
+
+    // SAFETY: InputEvent is a transparent wrapper around the kernel's input_event
+    let bytes =
+        unsafe { std::slice::from_raw_parts(events.as_ptr() as *const u8, std::mem::size_of_val(events.as_slice())) };
+    output.write_all(bytes)
+}
@@ -0,0 +1,38 @@
+use std::time::Instant;
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct SwitchState {
+    stable: i32,
+    pending: Option<(i32, Instant)>,
+}
+
+impl SwitchState {
+    pub(super) fn new(stable: i32) -> Self {
+        Self { stable, pending: None }
+    }
+    pub(super) fn stable(&self) -> i32 {
+        self.stable
+    }
+    pub(super) fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|(_, deadline)| deadline)
+    }
+    pub(super) fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+    pub(super) fn hold(&mut self, value: i32, deadline: Instant) {
+        self.pending = Some((value, deadline));
+    }
+    pub(super) fn cancel(&mut self) {
+        self.pending = None;
+    }
+    pub(super) fn settle(&mut self, now: Instant) -> Option<i32> {
+        match self.pending {
+            Some((value, deadline)) if deadline <= now => {
+                self.stable = value;
+                self.pending = None;
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use nix::sys::stat::{major, minor};
+
+/// Where udev keeps what it knows about every device, in files named after the device number (`c13:68`)
+const UDEV_DATA_DIR: &str = "/run/udev/data";
+
+/// The properties udev assigned to the device node (`ID_INPUT_KEYBOARD`, `ID_VENDOR_ID`, `ID_SERIAL`, ...);
+/// empty if udev has no record of it
+pub(super) fn udev_properties(path: &Path) -> HashMap<String, String> {
+    let Ok(metadata) = path.metadata() else {
+        return HashMap::new();
+    };
+    let rdev = metadata.rdev();
+    let data_path = Path::new(UDEV_DATA_DIR).join(format!("c{}:{}", major(rdev), minor(rdev)));
+    match std::fs::read_to_string(data_path) {
+        Ok(raw) => parse_udev_data(&raw),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Properties are the `E:KEY=VALUE` lines; the others are symlinks, tags and the like
+fn parse_udev_data(raw: &str) -> HashMap<String, String> {
+    raw.lines()
+        .filter_map(|line| line.strip_prefix("E:"))
+        .filter_map(|property| property.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_udev_data() {
+        let raw = "S:input/by-id/usb-ASUSTeK_Computer_Inc._N-KEY_Device-event-kbd\n\
+                   I:5462034\n\
+                   E:ID_INPUT=1\n\
+                   E:ID_INPUT_KEYBOARD=1\n\
+                   E:ID_VENDOR_ID=0b05\n\
+                   E:ID_SERIAL=ASUSTeK_Computer_Inc._N-KEY_Device\n\
+                   G:seat\n";
+        let properties = parse_udev_data(raw);
+        assert_eq!(properties.len(), 4);
+        assert_eq!(properties["ID_INPUT_KEYBOARD"], "1");
+        assert_eq!(properties["ID_VENDOR_ID"], "0b05");
+    }
+}
@@ -0,0 +1,31 @@
+use std::time::{Duration, SystemTime, SystemTimeError};
+
+#[derive(Debug, Clone)]
+pub(super) struct WheelState {
+    direction: i32,
+    ts: SystemTime,
+}
+
+impl Default for WheelState {
+    fn default() -> Self {
+        WheelState {
+            direction: 0,
+            ts: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
+
+impl WheelState {
+    pub(super) fn new(direction: i32, ts: SystemTime) -> Self {
+        Self { direction, ts }
+    }
+    pub(super) fn direction(&self) -> i32 {
+        self.direction
+    }
+    pub(super) fn time(&self) -> SystemTime {
+        self.ts
+    }
+    pub(super) fn duration_since(&self, now: &SystemTime) -> Result<Duration, SystemTimeError> {
+        now.duration_since(self.time())
+    }
+}